@@ -0,0 +1,122 @@
+//! How the balancing core scales with the map: `cargo bench -p balancer`.
+//!
+//! Maps are seeded, so every run measures the same work. Each benchmark id reads
+//! `<side>/<density %>/<groups>/<radius>` where it matters.
+
+use std::time::Duration;
+use balancer::{Balancer, Cell, ExecutionPlan, Grid, RadiusNeighborhood, StopCriteria, Threshold};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+const SEED: u64 = 7;
+
+/// A `side` x `side` map where each tile holds a group with probability `density`.
+/// Groups are angular sectors around the center, values are random in `1..=(group + 1) * 10`.
+fn seeded_map(side: usize, density: f64, group_count: usize) -> (Vec<Cell>, Vec<u32>) {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let grid = Grid::new(side, side);
+    let mut groups = vec![Cell::Empty; grid.len()];
+    let mut values = vec![0; grid.len()];
+    let center = side as f32 / 2.0;
+    for point in 0..grid.len() {
+        if !rng.gen_bool(density) {
+            continue
+        }
+        let (x, y) = grid.point(point);
+        let angle = (y as f32 - center).atan2(x as f32 - center) + std::f32::consts::PI;
+        let group = ((angle / std::f32::consts::TAU * group_count as f32) as usize).min(group_count - 1);
+        groups[point] = Cell::Group(group);
+        values[point] = rng.gen_range(1..=(group as u32 + 1) * 10);
+    }
+    (groups, values)
+}
+
+fn balancer(side: usize, density: f64, group_count: usize, radius: usize) -> Balancer {
+    let (groups, values) = seeded_map(side, density, group_count);
+    Balancer::new(groups, values, group_count, RadiusNeighborhood { grid: Grid::new(side, side), radius })
+}
+
+fn id(side: usize, density: f64, group_count: usize, radius: usize) -> String {
+    format!("{}/{}/{}/{}", side, (density * 100.0) as u32, group_count, radius)
+}
+
+/// `calculate_neighbors` runs when a `Balancer` is built, together with the centroids
+/// and the initial balance, which are linear in the map size.
+fn calculate_neighbors(c: &mut Criterion) {
+    let mut group = c.benchmark_group("calculate_neighbors");
+    group.sample_size(10);
+    for &(side, density, radius) in &[
+        (100, 0.33, 1), (100, 0.33, 2), (500, 0.33, 2), (500, 0.9, 2), (500, 0.33, 4), (2000, 0.33, 2)
+    ] {
+        let (groups, values) = seeded_map(side, density, 3);
+        group.bench_function(BenchmarkId::from_parameter(id(side, density, 3, radius)), |b| {
+            b.iter(|| Balancer::new(groups.clone(), values.clone(), 3,
+                RadiusNeighborhood { grid: Grid::new(side, side), radius }))
+        });
+    }
+    group.finish();
+}
+
+fn create_instruction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("create_instruction");
+    for &(side, density, group_count) in &[
+        (100, 0.33, 3), (500, 0.33, 3), (500, 0.33, 10), (500, 0.9, 3), (2000, 0.33, 3), (2000, 0.33, 40)
+    ] {
+        let mut balancer = balancer(side, density, group_count, 2);
+        let taker = balancer.find_min_group();
+        for plan in [ExecutionPlan::Eager, ExecutionPlan::Greedy] {
+            balancer.set_plan(plan);
+            let name = format!("{:?}/{}", plan, id(side, density, group_count, 2));
+            group.bench_function(BenchmarkId::from_parameter(name), |b| {
+                b.iter(|| balancer.create_instruction(taker))
+            });
+        }
+    }
+    group.finish();
+}
+
+/// A transfer and the transfer back, so that the map is the same for every iteration.
+fn reassign_group(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reassign_group");
+    for &(side, radius) in &[(100, 2), (500, 2), (500, 4), (2000, 2)] {
+        let mut balancer = balancer(side, 0.33, 3, radius);
+        let taker = balancer.find_min_group();
+        let point = balancer.boundary_points(taker)
+            .flat_map(|p| balancer.tile_neighbors()[p].clone())
+            .next()
+            .expect("the groups touch");
+        let giver = balancer.group(point).expect("a group tile");
+        group.bench_function(BenchmarkId::from_parameter(id(side, 0.33, 3, radius)), |b| {
+            b.iter(|| {
+                balancer.reassign_group(point, taker);
+                balancer.reassign_group(point, giver);
+            })
+        });
+    }
+    group.finish();
+}
+
+/// From the seeded map to a balance under 1% of the mean group sum, capped at 2000 transfers
+/// (200 on the 2000x2000 map, where every greedy transfer searches a long boundary).
+fn run_to_convergence(c: &mut Criterion) {
+    let mut group = c.benchmark_group("run");
+    group.sample_size(10).measurement_time(Duration::from_secs(20));
+    for &(side, group_count, max_transfers) in &[(100, 3, 2000), (100, 10, 2000), (500, 3, 2000), (2000, 3, 200)] {
+        let criteria = StopCriteria {
+            threshold: Some(Threshold::Mean(0.01)), max_transfers: Some(max_transfers), ..StopCriteria::default()
+        };
+        for plan in [ExecutionPlan::Eager, ExecutionPlan::Greedy] {
+            let mut start = balancer(side, 0.33, group_count, 2);
+            start.set_plan(plan);
+            let name = format!("{:?}/{}", plan, id(side, 0.33, group_count, 2));
+            group.bench_function(BenchmarkId::from_parameter(name), |b| {
+                b.iter_batched(|| start.clone(), |mut balancer| balancer.run(&criteria),
+                    criterion::BatchSize::LargeInput)
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, calculate_neighbors, create_instruction, reassign_group, run_to_convergence);
+criterion_main!(benches);
@@ -0,0 +1,262 @@
+//! Balances a map file without any rendering.
+//!
+//! Usage: balance <MAP> [-o OUT] [--groups N] [--neighborhood KIND] [--radius N] [--threshold T]
+//!     [--max-transfers N] [--time-limit SECS] [--no-improvement N] [--min-improvement F] [--plan PLAN] [--measure MEASURE] [--weights W0,W1,...] [--capacities C0,C1,...] [--contiguous]
+//!     [--export FILE]... [--value-property NAME[,NAME...]] [--group-property NAME]
+//!
+//! The map format is described in `balancer::io::read_grid`, `.csv` maps in `balancer::io::read_csv`.
+//! The balanced map is written to OUT (or stdout), followed by a summary as `//` comment lines,
+//! so the output can be fed back in.
+//! `.geojson` (or `.json`) maps are FeatureCollections, read with `balancer::geojson::read_geojson`
+//! from the value and group properties, and written back as GeoJSON with the final groups.
+//! `--export` also writes each tile's original and final group to a `.csv` or `.json` file.
+//! A `.csv` export puts the per-group sums and the balance in `<name>_summary.csv` next to it.
+//!
+//! `--threshold` is absolute, or relative with `total:F` (a fraction of the total value) or
+//! `mean:F` (a fraction of the mean group sum). Without it, each measure has its own default,
+//! see `BalanceMeasure::default_threshold`. The exit code tells how the run ended, see
+//! `SolverStatus::exit_code`; 1 is for errors and 2 for usage errors.
+
+use std::{fs::File, io::{BufReader, BufWriter, Write}, path::Path, process::ExitCode, time::Duration};
+use balancer::{
+    geojson::{read_geojson, write_geojson}, io::{read_map_file, write_export_file, write_grid}, parse_measure,
+    BalanceMeasure, Balancer, EightConnected, ExecutionPlan, FourConnected, MeanPairwiseDifference,
+    RadiusNeighborhood, SolverStatus, StopCriteria
+};
+
+const USAGE: &str = "usage: balance <MAP> [-o OUT] [--groups N] [--neighborhood radius|four|eight] [--radius N] \
+[--threshold T|total:F|mean:F] [--max-transfers N] [--time-limit SECS] [--no-improvement N] \
+[--min-improvement F] [--plan eager|greedy|distance-point|distance-centroid] \
+[--measure mean-diff|variance|spread|cv|target:T0,T1,...] [--weights W0,W1,...] \
+[--capacities C0,C1,...] [--contiguous] [--export FILE.csv|FILE.json]... \
+[--value-property NAME[,NAME...]] [--group-property NAME]";
+
+struct Args {
+    map: String,
+    out: Option<String>,
+    groups: Option<usize>,
+    neighborhood: String,
+    radius: usize,
+    stop: StopCriteria,
+    plan: ExecutionPlan,
+    measure: Box<dyn BalanceMeasure>,
+    weights: Option<Vec<f32>>,
+    capacities: Option<Vec<f32>>,
+    contiguous: bool,
+    exports: Vec<String>,
+    value_property: String,
+    group_property: String
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut map = None;
+    let mut out = None;
+    let mut groups = None;
+    let mut neighborhood = "radius".to_string();
+    let mut radius = 2;
+    let mut stop = StopCriteria::default();
+    let mut plan = ExecutionPlan::default();
+    let mut measure: Box<dyn BalanceMeasure> = Box::new(MeanPairwiseDifference);
+    let mut weights = None;
+    let mut capacities = None;
+    let mut contiguous = false;
+    let mut exports = Vec::new();
+    let mut value_property = "value".to_string();
+    let mut group_property = "group".to_string();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("missing value for {}", name));
+        match arg.as_str() {
+            "-o" | "--out" => out = Some(value(&arg)?),
+            "--groups" => groups = Some(value(&arg)?.parse().map_err(|e| format!("--groups: {}", e))?),
+            "--neighborhood" => neighborhood = value(&arg)?,
+            "--radius" => radius = value(&arg)?.parse().map_err(|e| format!("--radius: {}", e))?,
+            "--threshold" => stop.threshold = Some(value(&arg)?.parse()?),
+            "--max-transfers" | "--max-iterations" =>
+                stop.max_transfers = Some(value(&arg)?.parse().map_err(|e| format!("{}: {}", arg, e))?),
+            "--time-limit" => stop.time_limit = Some(Duration::try_from_secs_f64(
+                value(&arg)?.parse().map_err(|e| format!("--time-limit: {}", e))?
+            ).map_err(|e| format!("--time-limit: {}", e))?),
+            "--no-improvement" =>
+                stop.no_improvement = Some(value(&arg)?.parse().map_err(|e| format!("--no-improvement: {}", e))?),
+            "--min-improvement" =>
+                stop.min_improvement = value(&arg)?.parse().map_err(|e| format!("--min-improvement: {}", e))?,
+            "--plan" => plan = value(&arg)?.parse()?,
+            "--measure" => measure = parse_measure(&value(&arg)?)?,
+            "--weights" => weights = Some(parse_list(&value(&arg)?, "--weights")?),
+            "--capacities" => capacities = Some(parse_list(&value(&arg)?, "--capacities")?),
+            "--contiguous" => contiguous = true,
+            "--export" => exports.push(value(&arg)?),
+            "--value-property" => value_property = value(&arg)?,
+            "--group-property" => group_property = value(&arg)?,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if map.is_none() && !arg.starts_with('-') => map = Some(arg),
+            _ => return Err(format!("unexpected argument '{}'", arg))
+        }
+    }
+    if !["radius", "four", "eight"].contains(&neighborhood.as_str()) {
+        return Err(format!("unknown neighborhood '{}'", neighborhood))
+    }
+    Ok(Args {
+        map: map.ok_or("missing map file")?, out, groups, neighborhood, radius, stop, plan,
+        measure, weights, capacities, contiguous, exports, value_property, group_property
+    })
+}
+
+fn parse_list(s: &str, name: &str) -> Result<Vec<f32>, String> {
+    s.split(',')
+        .map(|w| w.trim().parse::<f32>().map_err(|e| format!("{}: {}", name, e)))
+        .collect()
+}
+
+fn write_summary<W: Write>(mut w: W, balancer: &Balancer, iterations: usize, status: SolverStatus
+    , initial_balance: f32) -> std::io::Result<()>
+{
+    writeln!(w, "// status: {}", status)?;
+    writeln!(w, "// iterations: {}", iterations)?;
+    writeln!(w, "// initial group_balance: {}", initial_balance)?;
+    writeln!(w, "// final group_balance: {}", balancer.group_balance())?;
+    for group in 0..balancer.group_count() {
+        let sums: Vec<String> = (0..balancer.attribute_count())
+            .map(|k| balancer.attribute_sums(k)[group].to_string())
+            .collect();
+        writeln!(w, "// group {} sum: {}", group, sums.join(","))?;
+    }
+    for (group, count) in balancer.component_counts().iter().enumerate() {
+        writeln!(w, "// group {} connected components: {}", group, count)?;
+    }
+    Ok(())
+}
+
+fn run(args: Args) -> Result<SolverStatus, Box<dyn std::error::Error>> {
+    let extension = Path::new(&args.map).extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    if extension == "geojson" || extension == "json" {
+        run_geojson(args)
+    } else {
+        run_grid(args)
+    }
+}
+
+fn run_grid(mut args: Args) -> Result<SolverStatus, Box<dyn std::error::Error>> {
+    let map = read_map_file(Path::new(&args.map))?;
+    // Groups without any tile on the map still count when given with --groups.
+    let group_count = args.groups.unwrap_or(map.group_count);
+    map.check_groups(group_count)?;
+    let grid = map.grid;
+    let (groups, values, k) = (map.tile_groups, map.tile_values, map.attribute_count);
+    let mut balancer = match args.neighborhood.as_str() {
+        "four" => Balancer::with_attributes(groups, values, k, group_count, FourConnected { grid }),
+        "eight" => Balancer::with_attributes(groups, values, k, group_count, EightConnected { grid }),
+        _ => Balancer::with_attributes(groups, values, k, group_count, RadiusNeighborhood { grid, radius: args.radius })
+    };
+    configure(&mut balancer, &mut args)?;
+    let initial_balance = balancer.group_balance();
+    let (iterations, status) = balancer.run(&args.stop);
+
+    let mut out: Box<dyn Write> = match &args.out {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(std::io::stdout()))
+    };
+    write_grid(&mut out, &grid, &balancer)?;
+    write_summary(&mut out, &balancer, iterations, status, initial_balance)?;
+    out.flush()?;
+    for path in &args.exports {
+        write_export_file(Path::new(path), &grid, &balancer)?;
+    }
+    if args.out.is_some() {
+        write_summary(std::io::stdout(), &balancer, iterations, status, initial_balance)?;
+    }
+    Ok(status)
+}
+
+/// Balances a GeoJSON FeatureCollection, neighbors being the features that share an edge.
+/// The result is GeoJSON, so the summary goes to stderr when it is written to stdout.
+fn run_geojson(mut args: Args) -> Result<SolverStatus, Box<dyn std::error::Error>> {
+    if !args.exports.is_empty() {
+        return Err("--export needs a grid or CSV map, GeoJSON results are written with -o".into())
+    }
+    let value_properties: Vec<&str> = args.value_property.split(',').map(|p| p.trim()).collect();
+    let map = read_geojson(BufReader::new(File::open(&args.map)?), &value_properties, &args.group_property)?;
+    let group_count = args.groups.unwrap_or(map.group_count);
+    if map.group_count > group_count {
+        return Err(format!("the map uses group {}, but there are only {} groups", map.group_count - 1, group_count).into())
+    }
+    let mut balancer = Balancer::with_attributes(map.tile_groups, map.tile_values, map.attribute_count
+        , group_count, map.neighborhood);
+    configure(&mut balancer, &mut args)?;
+    let initial_balance = balancer.group_balance();
+    let (iterations, status) = balancer.run(&args.stop);
+
+    match &args.out {
+        Some(path) => {
+            let mut out = BufWriter::new(File::create(path)?);
+            write_geojson(&mut out, &map.collection, &balancer, &args.group_property)?;
+            out.flush()?;
+            write_summary(std::io::stdout(), &balancer, iterations, status, initial_balance)?;
+        },
+        None => {
+            write_geojson(BufWriter::new(std::io::stdout()), &map.collection, &balancer, &args.group_property)?;
+            println!();
+            write_summary(std::io::stderr(), &balancer, iterations, status, initial_balance)?;
+        }
+    }
+    Ok(status)
+}
+
+/// Options that do not fit the map, reported like the other usage errors.
+#[derive(Debug)]
+struct UsageError(String);
+
+impl std::fmt::Display for UsageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+/// Applies the solver options to `balancer`.
+fn configure(balancer: &mut Balancer, args: &mut Args) -> Result<(), UsageError> {
+    let (k, group_count) = (balancer.attribute_count(), balancer.group_count());
+    if let Some(weights) = args.weights.take() {
+        if weights.len() != k {
+            return Err(UsageError(format!("the map has {} attributes, but {} weights were given", k, weights.len())))
+        }
+        if weights.iter().any(|&w| !(w >= 0.0 && w.is_finite())) {
+            return Err(UsageError("--weights must be non-negative finite values".to_string()))
+        }
+        balancer.set_weights(weights);
+    }
+    if let Some(capacities) = args.capacities.take() {
+        if capacities.len() != group_count || capacities.iter().any(|&c| !(c > 0.0 && c.is_finite())) {
+            return Err(UsageError(format!("--capacities needs {} positive finite values", group_count)))
+        }
+        balancer.set_capacities(capacities);
+    }
+    args.measure.check(group_count, k).map_err(|e| UsageError(format!("--measure: {}", e)))?;
+    balancer.set_plan(args.plan);
+    balancer.set_measure(std::mem::replace(&mut args.measure, Box::new(MeanPairwiseDifference)));
+    balancer.set_preserve_contiguity(args.contiguous);
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return ExitCode::from(2)
+        }
+    };
+    match run(args) {
+        Ok(status) => ExitCode::from(status.exit_code()),
+        Err(e) if e.is::<UsageError>() => {
+            eprintln!("{}\n{}", e, USAGE);
+            ExitCode::from(2)
+        },
+        Err(e) => {
+            eprintln!("balance: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
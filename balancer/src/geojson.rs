@@ -0,0 +1,226 @@
+//! GeoJSON territories: every Feature of a FeatureCollection is a unit (a zip code, a county)
+//! with a value and an initial group in its properties. Units are neighbors when their
+//! polygons share a stretch of border, whether or not both rings have a vertex at each end of it.
+
+use std::{collections::HashMap, fmt, io::{Read, Write}};
+use serde_json::{Map, Value};
+use crate::io::MAX_GROUPS;
+use crate::neighborhood::AdjacencyList;
+use crate::solver::{Balancer, Cell};
+
+/// Why a GeoJSON file could not be read.
+#[derive(Debug)]
+pub enum GeoJsonError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    /// The top level is not a FeatureCollection.
+    NotFeatureCollection,
+    /// A feature without the property, or with a value that is not a non-negative number.
+    /// Groups must also be whole numbers below `MAX_GROUPS`.
+    Property { feature: usize, name: String },
+    /// A feature whose geometry is not a Polygon or MultiPolygon.
+    Geometry { feature: usize },
+    Empty
+}
+
+impl fmt::Display for GeoJsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GeoJsonError::Io(e) => write!(f, "{}", e),
+            GeoJsonError::Json(e) => write!(f, "{}", e),
+            GeoJsonError::NotFeatureCollection => write!(f, "not a GeoJSON FeatureCollection"),
+            GeoJsonError::Property { feature, name } =>
+                write!(f, "feature {}: missing or bad property '{}'", feature, name),
+            GeoJsonError::Geometry { feature } =>
+                write!(f, "feature {}: geometry must be a Polygon or MultiPolygon", feature),
+            GeoJsonError::Empty => write!(f, "the collection has no features")
+        }
+    }
+}
+
+impl std::error::Error for GeoJsonError {}
+
+impl From<std::io::Error> for GeoJsonError {
+    fn from(e: std::io::Error) -> Self {
+        GeoJsonError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for GeoJsonError {
+    fn from(e: serde_json::Error) -> Self {
+        GeoJsonError::Json(e)
+    }
+}
+
+/// A GeoJSON map: point `i` is feature `i` of `collection`.
+pub struct GeoMap {
+    pub neighborhood: AdjacencyList,
+    pub tile_groups: Vec<Cell>,
+    /// `attribute_count` values per feature, one for each value property.
+    pub tile_values: Vec<u32>,
+    pub attribute_count: usize,
+    /// One more than the largest group id in the collection.
+    pub group_count: usize,
+    /// The collection as read, kept for `write_geojson`.
+    pub collection: Value
+}
+
+/// Reads a FeatureCollection. Each of `value_properties` is one attribute of a unit, and
+/// `group_property` its initial group; a unit with a null or missing group is left out of
+/// the balancing. Positions (for the distance plans) are the mean of the polygon vertices.
+/// Coordinates are compared to 7 decimal places when looking for shared borders. Two units
+/// are neighbors when an edge of one overlaps an edge of the other along a common line, so a
+/// long edge next to several shorter ones touches all of them; meeting at a corner is not enough.
+pub fn read_geojson<R: Read>(reader: R, value_properties: &[&str], group_property: &str)
+    -> Result<GeoMap, GeoJsonError>
+{
+    let collection: Value = serde_json::from_reader(reader)?;
+    if collection.get("type").and_then(Value::as_str) != Some("FeatureCollection") {
+        return Err(GeoJsonError::NotFeatureCollection)
+    }
+    let features = collection.get("features").and_then(Value::as_array)
+        .ok_or(GeoJsonError::NotFeatureCollection)?;
+    if features.is_empty() {
+        return Err(GeoJsonError::Empty)
+    }
+    let mut tile_groups = Vec::with_capacity(features.len());
+    let mut tile_values = Vec::with_capacity(features.len() * value_properties.len());
+    let mut positions = Vec::with_capacity(features.len());
+    // every polygon edge, by the line it lies on
+    let mut lines: HashMap<Line, Vec<Segment>> = HashMap::new();
+    for (i, feature) in features.iter().enumerate() {
+        let properties = feature.get("properties").and_then(Value::as_object);
+        let property = |name: &str| GeoJsonError::Property { feature: i, name: name.to_string() };
+        tile_groups.push(match properties.and_then(|p| p.get(group_property)) {
+            None | Some(Value::Null) => Cell::Empty,
+            Some(v) => Cell::Group(v.as_u64().and_then(|g| usize::try_from(g).ok()).filter(|&g| g < MAX_GROUPS)
+                .ok_or_else(|| property(group_property))?)
+        });
+        for &name in value_properties {
+            let value = properties.and_then(|p| p.get(name)).and_then(Value::as_f64)
+                .filter(|v| *v >= 0.0)
+                .ok_or_else(|| property(name))?;
+            tile_values.push(value.round() as u32);
+        }
+
+        let rings = rings(feature.get("geometry")).ok_or(GeoJsonError::Geometry { feature: i })?;
+        let (mut sx, mut sy, mut n) = (0.0, 0.0, 0.0);
+        for ring in &rings {
+            for pair in ring.windows(2) {
+                let (a, b) = (quantize(pair[0]), quantize(pair[1]));
+                if a != b {
+                    let (line, start, end) = on_line(a, b);
+                    lines.entry(line).or_default().push(Segment { start, end, feature: i });
+                }
+            }
+            // the last vertex of a ring repeats the first one
+            for &(x, y) in &ring[..ring.len().saturating_sub(1)] {
+                sx += x;
+                sy += y;
+                n += 1.0;
+            }
+        }
+        positions.push(if n > 0.0 { ((sx / n) as f32, (sy / n) as f32) } else { (0.0, 0.0) });
+    }
+    let group_count = tile_groups.iter().filter_map(|c| c.group()).max().map_or(0, |g| g + 1);
+    let edges: Vec<(usize, usize)> = lines.into_values().flat_map(overlapping_features).collect();
+    let neighborhood = AdjacencyList::from_edges(features.len(), &edges).with_positions(positions);
+    Ok(GeoMap {
+        neighborhood, tile_groups, tile_values, attribute_count: value_properties.len(), group_count, collection
+    })
+}
+
+/// The linear rings of a Polygon or MultiPolygon geometry.
+fn rings(geometry: Option<&Value>) -> Option<Vec<Vec<(f64, f64)>>> {
+    let geometry = geometry?;
+    let coordinates = geometry.get("coordinates")?;
+    let polygons = match geometry.get("type")?.as_str()? {
+        "Polygon" => vec![coordinates],
+        "MultiPolygon" => coordinates.as_array()?.iter().collect(),
+        _ => return None
+    };
+    let mut rings = Vec::new();
+    for polygon in polygons {
+        for ring in polygon.as_array()? {
+            let ring = ring.as_array()?.iter()
+                .map(|p| Some((p.get(0)?.as_f64()?, p.get(1)?.as_f64()?)))
+                .collect::<Option<Vec<_>>>()?;
+            rings.push(ring);
+        }
+    }
+    Some(rings)
+}
+
+/// A polygon vertex, in units of 1e-7.
+type Vertex = (i64, i64);
+
+fn quantize((x, y): (f64, f64)) -> Vertex {
+    ((x * 1e7).round() as i64, (y * 1e7).round() as i64)
+}
+
+/// A line through the quantized plane: its direction `(dx, dy)`, reduced and pointing to
+/// positive x (or positive y when vertical), and `dy * x - dx * y`, the same for all its points.
+type Line = (i64, i64, i128);
+
+/// The part of a line covered by an edge of `feature`, from `start` to `end` along the line.
+struct Segment {
+    start: i128,
+    end: i128,
+    feature: usize
+}
+
+/// The line through `a` and `b` (which differ), and where they are on it, in increasing order.
+fn on_line(a: Vertex, b: Vertex) -> (Line, i128, i128) {
+    let (mut dx, mut dy) = (b.0 - a.0, b.1 - a.1);
+    let divisor = gcd(dx.unsigned_abs(), dy.unsigned_abs()) as i64;
+    (dx, dy) = (dx / divisor, dy / divisor);
+    if dx < 0 || (dx == 0 && dy < 0) {
+        (dx, dy) = (-dx, -dy);
+    }
+    let along = |(x, y): Vertex| x as i128 * dx as i128 + y as i128 * dy as i128;
+    let offset = dy as i128 * a.0 as i128 - dx as i128 * a.1 as i128;
+    let (s, e) = (along(a), along(b));
+    ((dx, dy, offset), s.min(e), s.max(e))
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Pairs of features with segments on the same line that overlap by more than a point.
+fn overlapping_features(mut segments: Vec<Segment>) -> Vec<(usize, usize)> {
+    segments.sort_by_key(|s| s.start);
+    let mut pairs = Vec::new();
+    let mut open: Vec<&Segment> = Vec::new();
+    for segment in &segments {
+        open.retain(|o| o.end > segment.start);
+        pairs.extend(open.iter().filter(|o| o.feature != segment.feature).map(|o| (o.feature, segment.feature)));
+        open.push(segment);
+    }
+    pairs
+}
+
+/// Writes `collection` (as read into a `GeoMap`) with `group_property` of every feature set to
+/// its group in `balancer`, and the initial group kept in `original_<group_property>`.
+pub fn write_geojson<W: Write>(writer: W, collection: &Value, balancer: &Balancer, group_property: &str)
+    -> Result<(), GeoJsonError>
+{
+    let mut collection = collection.clone();
+    if let Some(features) = collection.get_mut("features").and_then(Value::as_array_mut) {
+        for (point, feature) in features.iter_mut().enumerate() {
+            let Some(object) = feature.as_object_mut() else { continue };
+            let properties = object.entry("properties").or_insert_with(|| Value::Object(Map::new()));
+            if !properties.is_object() {
+                *properties = Value::Object(Map::new());
+            }
+            let properties = properties.as_object_mut().expect("properties is an object");
+            if let Some(original) = balancer.initial_group(point) {
+                properties.insert(format!("original_{}", group_property), original.into());
+            }
+            let group = balancer.group(point).map_or(Value::Null, Value::from);
+            properties.insert(group_property.to_string(), group);
+        }
+    }
+    serde_json::to_writer(writer, &collection)?;
+    Ok(())
+}
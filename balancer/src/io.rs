@@ -0,0 +1,365 @@
+use std::{fmt, fs::File, io::{BufRead, BufReader, BufWriter, Write}, path::{Path, PathBuf}};
+use itertools::Itertools;
+use crate::neighborhood::Grid;
+use crate::solver::{Balancer, Cell};
+
+/// Why a map file could not be read.
+#[derive(Debug)]
+pub enum MapFileError {
+    Io(std::io::Error),
+    /// A cell that is not `#`, `.` or `<group>:<values>`.
+    BadCell { line: usize, cell: String },
+    /// A cell with a different number of values than the first tile.
+    Attributes { line: usize, expected: usize, found: usize },
+    /// A line with a different number of cells than the first one.
+    Ragged { line: usize, expected: usize, found: usize },
+    /// A CSV field that does not parse.
+    BadField { line: usize, field: &'static str, value: String },
+    /// A CSV row for a tile that was already given.
+    DuplicateTile { line: usize, x: usize, y: usize },
+    /// A group id that is not below the number of groups.
+    GroupOutOfRange { group: usize, group_count: usize },
+    /// CSV coordinates that make a grid of more than `MAX_TILES` tiles.
+    TooLarge { width: usize, height: usize },
+    /// A group id of `MAX_GROUPS` or more.
+    TooManyGroups { line: usize, group: usize },
+    Empty
+}
+
+/// The most tiles a CSV map may span, walls and empty tiles included.
+pub const MAX_TILES: usize = 25_000_000;
+
+/// Group ids of a map file must be below this.
+pub const MAX_GROUPS: usize = 100_000;
+
+impl fmt::Display for MapFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapFileError::Io(e) => write!(f, "{}", e),
+            MapFileError::BadCell { line, cell } =>
+                write!(f, "line {}: cannot parse cell '{}'", line, cell),
+            MapFileError::Attributes { line, expected, found } =>
+                write!(f, "line {}: expected {} values per tile, found {}", line, expected, found),
+            MapFileError::Ragged { line, expected, found } =>
+                write!(f, "line {}: expected {} cells, found {}", line, expected, found),
+            MapFileError::BadField { line, field, value } =>
+                write!(f, "line {}: bad {} '{}'", line, field, value),
+            MapFileError::DuplicateTile { line, x, y } =>
+                write!(f, "line {}: tile ({}, {}) is given twice", line, x, y),
+            MapFileError::GroupOutOfRange { group, group_count } =>
+                write!(f, "the map uses group {}, but there are only {} groups", group, group_count),
+            MapFileError::TooLarge { width, height } =>
+                write!(f, "the map would be {} x {} tiles, more than the {} allowed", width, height, MAX_TILES),
+            MapFileError::TooManyGroups { line, group } =>
+                write!(f, "line {}: group {} is too large, groups must be below {}", line, group, MAX_GROUPS),
+            MapFileError::Empty => write!(f, "the map has no tiles")
+        }
+    }
+}
+
+impl std::error::Error for MapFileError {}
+
+impl From<std::io::Error> for MapFileError {
+    fn from(e: std::io::Error) -> Self {
+        MapFileError::Io(e)
+    }
+}
+
+/// Groups and values of a grid, indexed by `grid.index((x, y))`. Values are laid out
+/// as in `Balancer::with_attributes`.
+pub struct GridMap {
+    pub grid: Grid,
+    pub tile_groups: Vec<Cell>,
+    pub tile_values: Vec<u32>,
+    pub attribute_count: usize,
+    /// One more than the largest group id on the map.
+    pub group_count: usize
+}
+
+impl GridMap {
+    /// Checks that the map fits in `group_count` groups.
+    pub fn check_groups(&self, group_count: usize) -> Result<(), MapFileError> {
+        if self.group_count > group_count {
+            return Err(MapFileError::GroupOutOfRange { group: self.group_count - 1, group_count })
+        }
+        Ok(())
+    }
+}
+
+/// Reads a map file: `read_csv` for `.csv` files, `read_grid` for anything else.
+pub fn read_map_file(path: &Path) -> Result<GridMap, MapFileError> {
+    let reader = BufReader::new(File::open(path)?);
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("csv") => read_csv(reader),
+        _ => read_grid(reader)
+    }
+}
+
+/// Reads a map from CSV rows of `x,y,value,group`, one row per tile. Several values per tile
+/// are given as more columns before the group: `x,y,v0,v1,...,group`. `x` and `y` are tile
+/// coordinates starting at 0, the grid is as large as the largest ones, up to `MAX_TILES`
+/// tiles, and tiles without a row are empty. A group of `#` makes the tile a wall. A first
+/// line that does not start with a number is a header. Only one column name matters in it:
+/// an `original_group` column, as in the files from `write_csv`, is left out, so that an
+/// exported map reads back in with its final groups.
+pub fn read_csv<R: BufRead>(reader: R) -> Result<GridMap, MapFileError> {
+    let mut rows: Vec<(usize, usize, usize, Cell, Vec<u32>)> = Vec::new();
+    let mut attribute_count = None;
+    let mut skipped_column = None;
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let trimmed = line.trim();
+        if i == 0 && !trimmed.is_empty() && !trimmed.starts_with(|c: char| c.is_ascii_digit()) {
+            skipped_column = trimmed.split(',').position(|name| name.trim() == "original_group");
+            continue
+        }
+        if trimmed.is_empty() {
+            continue
+        }
+        let mut fields: Vec<&str> = trimmed.split(',').map(|f| f.trim()).collect();
+        if let Some(column) = skipped_column.filter(|&c| c < fields.len()) {
+            fields.remove(column);
+        }
+        if fields.len() < 4 {
+            return Err(MapFileError::BadField { line: i + 1, field: "row", value: trimmed.to_string() })
+        }
+        let field = |name: &'static str, value: &str| MapFileError::BadField {
+            line: i + 1, field: name, value: value.to_string()
+        };
+        let x: usize = fields[0].parse().map_err(|_| field("x", fields[0]))?;
+        let y: usize = fields[1].parse().map_err(|_| field("y", fields[1]))?;
+        let group = fields[fields.len() - 1];
+        let values = fields[2..fields.len() - 1].iter()
+            .map(|v| v.parse::<u32>().map_err(|_| field("value", v)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let cell = if group == "#" {
+            Cell::Wall
+        } else {
+            Cell::Group(group.parse().map_err(|_| field("group", group))?)
+        };
+        if let Some(group) = cell.group().filter(|&g| g >= MAX_GROUPS) {
+            return Err(MapFileError::TooManyGroups { line: i + 1, group })
+        }
+        if cell.group().is_some() {
+            let expected = *attribute_count.get_or_insert(values.len());
+            if values.len() != expected {
+                return Err(MapFileError::Attributes { line: i + 1, expected, found: values.len() })
+            }
+        }
+        rows.push((i + 1, x, y, cell, values));
+    }
+    if rows.is_empty() {
+        return Err(MapFileError::Empty)
+    }
+    let width = rows.iter().map(|r| r.1).max().unwrap_or(0).saturating_add(1);
+    let height = rows.iter().map(|r| r.2).max().unwrap_or(0).saturating_add(1);
+    if width.checked_mul(height).is_none_or(|tiles| tiles > MAX_TILES) {
+        return Err(MapFileError::TooLarge { width, height })
+    }
+    let grid = Grid::new(width, height);
+    let attribute_count = attribute_count.unwrap_or(1);
+    let mut tile_groups = vec![Cell::Empty; grid.len()];
+    let mut tile_values = vec![0; grid.len() * attribute_count];
+    let mut given = vec![false; grid.len()];
+    for (line, x, y, cell, values) in rows {
+        let point = grid.index((x, y));
+        if given[point] {
+            return Err(MapFileError::DuplicateTile { line, x, y })
+        }
+        given[point] = true;
+        tile_groups[point] = cell;
+        if cell.group().is_some() {
+            tile_values[point * attribute_count..(point + 1) * attribute_count].copy_from_slice(&values);
+        }
+    }
+    let group_count = tile_groups.iter().filter_map(|c| c.group()).max().map_or(0, |g| g + 1);
+    Ok(GridMap { grid, tile_groups, tile_values, attribute_count, group_count })
+}
+
+/// Reads a grid map. Every non-comment line is one row of the grid (a fixed y, starting at 0),
+/// made of whitespace separated cells along x: `#` is a wall, `.` is an empty tile and
+/// `<group>:<value>` is a tile of a group. A tile with several attributes lists them all,
+/// comma separated (`<group>:<v0>,<v1>,...`), and every tile must have the same number.
+/// Blank lines and lines starting with `//` are comments.
+pub fn read_grid<R: BufRead>(reader: R) -> Result<GridMap, MapFileError> {
+    let mut rows: Vec<Vec<(Cell, Vec<u32>)>> = Vec::new();
+    let mut attribute_count = None;
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with("//") {
+            continue
+        }
+        let row = trimmed.split_whitespace()
+            .map(|cell| parse_cell(cell, i + 1))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (cell, values) in row.iter() {
+            if cell.group().is_none() {
+                continue
+            }
+            let expected = *attribute_count.get_or_insert(values.len());
+            if values.len() != expected {
+                return Err(MapFileError::Attributes { line: i + 1, expected, found: values.len() })
+            }
+        }
+        if let Some(first) = rows.first() {
+            if first.len() != row.len() {
+                return Err(MapFileError::Ragged { line: i + 1, expected: first.len(), found: row.len() })
+            }
+        }
+        rows.push(row);
+    }
+    if rows.is_empty() {
+        return Err(MapFileError::Empty)
+    }
+    let grid = Grid::new(rows[0].len(), rows.len());
+    let mut tile_groups = vec![Cell::Empty; grid.len()];
+    let attribute_count = attribute_count.unwrap_or(1);
+    let mut tile_values = vec![0; grid.len() * attribute_count];
+    for (y, row) in rows.iter().enumerate() {
+        for (x, (group, values)) in row.iter().enumerate() {
+            let point = grid.index((x, y));
+            tile_groups[point] = *group;
+            if group.group().is_some() {
+                tile_values[point * attribute_count..(point + 1) * attribute_count].copy_from_slice(values);
+            }
+        }
+    }
+    let group_count = tile_groups.iter().filter_map(|c| c.group()).max().map_or(0, |g| g + 1);
+    Ok(GridMap { grid, tile_groups, tile_values, attribute_count, group_count })
+}
+
+fn parse_cell(cell: &str, line: usize) -> Result<(Cell, Vec<u32>), MapFileError> {
+    match cell {
+        "#" => return Ok((Cell::Wall, Vec::new())),
+        "." => return Ok((Cell::Empty, Vec::new())),
+        _ => {}
+    }
+    let bad = || MapFileError::BadCell { line, cell: cell.to_string() };
+    let (group, values) = cell.split_once(':').ok_or_else(bad)?;
+    let group: usize = group.parse().map_err(|_| bad())?;
+    if group >= MAX_GROUPS {
+        return Err(MapFileError::TooManyGroups { line, group })
+    }
+    let values = values.split(',')
+        .map(|v| v.parse::<u32>().map_err(|_| bad()))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((Cell::Group(group), values))
+}
+
+/// Writes the current assignment of `balancer`, whose points are the tiles of `grid`,
+/// in the format read by `read_grid`.
+pub fn write_grid<W: Write>(mut writer: W, grid: &Grid, balancer: &Balancer) -> std::io::Result<()> {
+    for y in 0..grid.height {
+        let row: Vec<String> = (0..grid.width).map(|x| {
+            let point = grid.index((x, y));
+            match balancer.cell(point) {
+                Cell::Wall => "#".to_string(),
+                Cell::Empty => ".".to_string(),
+                Cell::Group(g) => format!("{}:{}", g, balancer.values(point).iter().join(","))
+            }
+        }).collect();
+        writeln!(writer, "{}", row.join(" "))?;
+    }
+    Ok(())
+}
+
+/// Writes the result of a run to `path`: `write_json` for `.json` files, `write_csv` for anything
+/// else, with `write_csv_summary` to `csv_summary_path(path)`.
+pub fn write_export_file(path: &Path, grid: &Grid, balancer: &Balancer) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("json") => write_json(&mut writer, grid, balancer)?,
+        _ => {
+            write_csv(&mut writer, grid, balancer)?;
+            let mut summary = BufWriter::new(File::create(csv_summary_path(path))?);
+            write_csv_summary(&mut summary, balancer)?;
+            summary.flush()?;
+        }
+    }
+    writer.flush()
+}
+
+/// Writes one `x,y,value,original_group,group` row per tile, with one `value<k>` column per
+/// attribute when there are several. Walls have `#` as both groups and empty tiles are left
+/// out, so that `read_csv` reads the file back as the balanced map. The per-group sums and
+/// the final balance are written apart, by `write_csv_summary`.
+pub fn write_csv<W: Write>(mut writer: W, grid: &Grid, balancer: &Balancer) -> std::io::Result<()> {
+    let value_columns = match balancer.attribute_count() {
+        1 => "value".to_string(),
+        n => (0..n).map(|k| format!("value{}", k)).join(",")
+    };
+    writeln!(writer, "x,y,{},original_group,group", value_columns)?;
+    let walls = std::iter::repeat_n("0", balancer.attribute_count()).join(",");
+    for point in 0..balancer.len() {
+        let (x, y) = grid.point(point);
+        match (balancer.group(point), balancer.initial_group(point)) {
+            (Some(group), Some(original)) =>
+                writeln!(writer, "{},{},{},{},{}", x, y, balancer.values(point).iter().join(","), original, group)?,
+            _ if balancer.cell(point) == Cell::Wall => writeln!(writer, "{},{},{},#,#", x, y, walls)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Writes the final `group_balance` and the sum of every group as `name,value` rows:
+/// `group_<g>_sum`, or `group_<g>_value<k>_sum` for each attribute when there are several.
+pub fn write_csv_summary<W: Write>(mut writer: W, balancer: &Balancer) -> std::io::Result<()> {
+    writeln!(writer, "name,value")?;
+    writeln!(writer, "group_balance,{}", balancer.group_balance())?;
+    for group in 0..balancer.group_count() {
+        match balancer.attribute_count() {
+            1 => writeln!(writer, "group_{}_sum,{}", group, balancer.attribute_sums(0)[group])?,
+            n => for k in 0..n {
+                writeln!(writer, "group_{}_value{}_sum,{}", group, k, balancer.attribute_sums(k)[group])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Where `write_export_file` puts the summary of a CSV export: `<name>_summary.csv` next to it.
+pub fn csv_summary_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().map_or_else(String::new, |s| s.to_string_lossy().into_owned());
+    path.with_file_name(format!("{}_summary.csv", stem))
+}
+
+/// Writes the result of a run as a JSON object: `group_balance`, `group_sums` (one list per
+/// attribute, indexed by group) and `tiles`, one object per tile with `x`, `y`, `value`
+/// (a list when there are several attributes), `original_group` and `group`.
+pub fn write_json<W: Write>(mut writer: W, grid: &Grid, balancer: &Balancer) -> std::io::Result<()> {
+    let sums = (0..balancer.attribute_count())
+        .map(|k| format!("[{}]", balancer.attribute_sums(k).iter().join(",")))
+        .join(",");
+    writeln!(writer, "{{")?;
+    writeln!(writer, "  \"group_balance\": {},", json_number(balancer.group_balance()))?;
+    writeln!(writer, "  \"group_sums\": [{}],", sums)?;
+    writeln!(writer, "  \"tiles\": [")?;
+    let mut first = true;
+    for point in 0..balancer.len() {
+        if let (Some(group), Some(original)) = (balancer.group(point), balancer.initial_group(point)) {
+            let (x, y) = grid.point(point);
+            let value = match balancer.values(point) {
+                [v] => v.to_string(),
+                values => format!("[{}]", values.iter().join(","))
+            };
+            if !first {
+                writeln!(writer, ",")?;
+            }
+            first = false;
+            write!(writer, "    {{\"x\": {}, \"y\": {}, \"value\": {}, \"original_group\": {}, \"group\": {}}}",
+                x, y, value, original, group)?;
+        }
+    }
+    if !first {
+        writeln!(writer)?;
+    }
+    writeln!(writer, "  ]")?;
+    writeln!(writer, "}}")
+}
+
+/// JSON has no infinity or NaN, those become `null`.
+fn json_number(v: f32) -> String {
+    if v.is_finite() { v.to_string() } else { "null".to_string() }
+}
@@ -0,0 +1,24 @@
+//! The balancing algorithm from the readme, free of any rendering.
+//!
+//! The Bevy demo in the root crate is one consumer of [`Balancer`]; anything
+//! else that needs balanced groups (a backend service, a batch job) can use
+//! this crate on its own.
+
+pub mod geojson;
+pub mod io;
+mod measure;
+mod neighborhood;
+mod plan;
+mod schedule;
+mod solver;
+mod stop;
+
+pub use measure::{
+    parse_measure, BalanceMeasure, CoefficientOfVariation, MaxMinSpread, MeanPairwiseDifference,
+    TargetDistance, Variance
+};
+pub use neighborhood::{AdjacencyList, EightConnected, FourConnected, Grid, Neighborhood, RadiusNeighborhood};
+pub use plan::{DistanceAnchor, ExecutionPlan};
+pub use schedule::{order_tour, plan_batch, tour_length, TourOrder};
+pub use solver::{calc_balance, Balancer, Cell, Instruction, Preview};
+pub use stop::{RunTracker, SolverStatus, StopCriteria, Threshold, DEFAULT_THRESHOLD};
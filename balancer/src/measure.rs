@@ -0,0 +1,186 @@
+use std::fmt;
+use itertools::Itertools;
+use crate::stop::{Threshold, DEFAULT_THRESHOLD};
+
+/// How unbalanced a set of group sums is. Lower is better, zero is perfectly balanced.
+pub trait BalanceMeasure: fmt::Debug + Send + Sync {
+    fn measure(&self, group_sum: &[f64]) -> f32;
+
+    /// The group that most needs to grow, where the algorithm looks for a transfer first.
+    /// Defaults to the group with the smallest sum. With several attributes it is given each
+    /// group's weighted share of the attribute totals instead of its sums.
+    fn neediest_group(&self, group_sum: &[f64]) -> usize {
+        argmin(group_sum)
+    }
+
+    /// Whether the measure fits a map with these many groups and attributes.
+    fn check(&self, _group_count: usize, _attribute_count: usize) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Whether group sums are divided by their group's capacity before they are measured.
+    /// Measures with targets of their own compare them to the plain sums instead.
+    fn uses_capacities(&self) -> bool {
+        true
+    }
+
+    /// The threshold a run stops at when none is given. Defaults to `DEFAULT_THRESHOLD`,
+    /// which suits measures in the units of the values.
+    fn default_threshold(&self) -> Threshold {
+        Threshold::Absolute(DEFAULT_THRESHOLD)
+    }
+}
+
+/// Index of the smallest value, the first one on ties.
+pub(crate) fn argmin(data: &[f64]) -> usize {
+    let mut min_idx: usize = 0;
+    let mut min_val = f64::INFINITY;
+    for (i, &v) in data.iter().enumerate() {
+        if v < min_val {
+            min_val = v;
+            min_idx = i;
+        }
+    }
+    min_idx
+}
+
+/// Mean pairwise absolute difference, the measure from the readme. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MeanPairwiseDifference;
+
+impl BalanceMeasure for MeanPairwiseDifference {
+    fn measure(&self, group_sum: &[f64]) -> f32 {
+        let mut s = 0.0;
+        let mut counter = 0.0;
+        for (a, b) in group_sum.iter().tuple_combinations() {
+            s += (a - b).abs();
+            counter += 1.0;
+        }
+        if counter == 0.0 {
+            return 0.0
+        }
+        (s / counter) as f32
+    }
+}
+
+/// Population variance of the group sums.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Variance;
+
+impl BalanceMeasure for Variance {
+    fn measure(&self, group_sum: &[f64]) -> f32 {
+        variance(group_sum) as f32
+    }
+
+    /// A standard deviation of `DEFAULT_THRESHOLD`.
+    fn default_threshold(&self) -> Threshold {
+        Threshold::Absolute(DEFAULT_THRESHOLD * DEFAULT_THRESHOLD)
+    }
+}
+
+/// Largest group sum minus the smallest one.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MaxMinSpread;
+
+impl BalanceMeasure for MaxMinSpread {
+    fn measure(&self, group_sum: &[f64]) -> f32 {
+        match group_sum.iter().minmax().into_option() {
+            Some((min, max)) => (max - min) as f32,
+            None => 0.0
+        }
+    }
+}
+
+/// Standard deviation over mean of the group sums. Zero when all sums are zero.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CoefficientOfVariation;
+
+impl BalanceMeasure for CoefficientOfVariation {
+    fn measure(&self, group_sum: &[f64]) -> f32 {
+        let mean = mean(group_sum);
+        if mean == 0.0 {
+            return 0.0
+        }
+        (variance(group_sum).sqrt() / mean) as f32
+    }
+
+    /// A standard deviation of 5% of the mean group sum.
+    fn default_threshold(&self) -> Threshold {
+        Threshold::Absolute(0.05)
+    }
+}
+
+/// Mean absolute distance of each group sum to its own target.
+#[derive(Clone, Debug)]
+pub struct TargetDistance {
+    /// One target per group.
+    pub targets: Vec<f64>
+}
+
+impl BalanceMeasure for TargetDistance {
+    fn measure(&self, group_sum: &[f64]) -> f32 {
+        assert_eq!(group_sum.len(), self.targets.len(), "one target per group");
+        if group_sum.is_empty() {
+            return 0.0
+        }
+        let s: f64 = group_sum.iter().zip(self.targets.iter()).map(|(c, t)| (c - t).abs()).sum();
+        (s / group_sum.len() as f64) as f32
+    }
+
+    /// The targets are in the units of a single attribute, so several attributes are rejected.
+    fn check(&self, group_count: usize, attribute_count: usize) -> Result<(), String> {
+        if attribute_count > 1 {
+            return Err(format!("targets are for a single value per tile, but the map has {} values per tile",
+                attribute_count))
+        }
+        if self.targets.len() != group_count {
+            return Err(format!("the map has {} groups, but {} targets were given", group_count, self.targets.len()))
+        }
+        Ok(())
+    }
+
+    /// The targets already say how large each group should be.
+    fn uses_capacities(&self) -> bool {
+        false
+    }
+
+    /// The group furthest below its target.
+    fn neediest_group(&self, group_sum: &[f64]) -> usize {
+        let deficits: Vec<f64> = group_sum.iter().zip(self.targets.iter()).map(|(c, t)| c - t).collect();
+        argmin(&deficits)
+    }
+}
+
+fn mean(data: &[f64]) -> f64 {
+    if data.is_empty() {
+        return 0.0
+    }
+    data.iter().sum::<f64>() / data.len() as f64
+}
+
+fn variance(data: &[f64]) -> f64 {
+    if data.is_empty() {
+        return 0.0
+    }
+    let mean = mean(data);
+    data.iter().map(|c| (c - mean) * (c - mean)).sum::<f64>() / data.len() as f64
+}
+
+/// Parses `mean-diff`, `variance`, `spread`, `cv` or `target:<t0>,<t1>,...`.
+pub fn parse_measure(s: &str) -> Result<Box<dyn BalanceMeasure>, String> {
+    match s {
+        "mean-diff" => Ok(Box::new(MeanPairwiseDifference)),
+        "variance" => Ok(Box::new(Variance)),
+        "spread" => Ok(Box::new(MaxMinSpread)),
+        "cv" => Ok(Box::new(CoefficientOfVariation)),
+        _ => match s.strip_prefix("target:") {
+            Some(list) => {
+                let targets = list.split(',')
+                    .map(|t| t.trim().parse::<f64>().map_err(|e| format!("target '{}': {}", t, e)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(Box::new(TargetDistance { targets }))
+            },
+            None => Err(format!("unknown balance measure '{}'", s))
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::fmt;
+
+/// Which points are neighbors of each other. Points are the indices `0..len()`.
+///
+/// Neighbors do not need a notion of distance (zip codes that share a border are neighbors),
+/// but each point has a position, used by the Distance execution plan.
+pub trait Neighborhood: fmt::Debug + Send + Sync {
+    /// Number of points.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The neighbors of `point`, not including `point` itself. Must be symmetric:
+    /// `q` is a neighbor of `p` exactly when `p` is a neighbor of `q`.
+    fn neighbors(&self, point: usize) -> Vec<usize>;
+
+    /// Where `point` is, for distance based execution plans.
+    fn position(&self, point: usize) -> (f32, f32);
+}
+
+/// A `width` x `height` grid. Tile `(x, y)` is point `x * height + y`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Grid {
+    pub width: usize,
+    pub height: usize
+}
+
+impl Grid {
+    pub fn new(width: usize, height: usize) -> Grid {
+        Grid { width, height }
+    }
+
+    pub fn len(&self) -> usize {
+        self.width * self.height
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn index(&self, (x, y): (usize, usize)) -> usize {
+        x * self.height + y
+    }
+
+    pub fn point(&self, index: usize) -> (usize, usize) {
+        (index / self.height, index % self.height)
+    }
+
+    /// Tiles within `radius` of `index` in both coordinates, excluding `index`.
+    fn window(&self, index: usize, radius: usize) -> Vec<usize> {
+        let (x, y) = self.point(index);
+        let x_max = (self.width - 1).min(x + radius);
+        let y_max = (self.height - 1).min(y + radius);
+        let mut out = Vec::new();
+        for xx in x.saturating_sub(radius)..=x_max {
+            for yy in y.saturating_sub(radius)..=y_max {
+                if (xx, yy) != (x, y) {
+                    out.push(self.index((xx, yy)));
+                }
+            }
+        }
+        out
+    }
+
+    fn grid_position(&self, index: usize) -> (f32, f32) {
+        let (x, y) = self.point(index);
+        (x as f32, y as f32)
+    }
+}
+
+/// Tiles within `radius` of each other in both coordinates (a square window). This is
+/// what the demo uses.
+#[derive(Clone, Copy, Debug)]
+pub struct RadiusNeighborhood {
+    pub grid: Grid,
+    pub radius: usize
+}
+
+impl Neighborhood for RadiusNeighborhood {
+    fn len(&self) -> usize {
+        self.grid.len()
+    }
+
+    fn neighbors(&self, point: usize) -> Vec<usize> {
+        self.grid.window(point, self.radius)
+    }
+
+    fn position(&self, point: usize) -> (f32, f32) {
+        self.grid.grid_position(point)
+    }
+}
+
+/// Tiles sharing an edge.
+#[derive(Clone, Copy, Debug)]
+pub struct FourConnected {
+    pub grid: Grid
+}
+
+impl Neighborhood for FourConnected {
+    fn len(&self) -> usize {
+        self.grid.len()
+    }
+
+    fn neighbors(&self, point: usize) -> Vec<usize> {
+        let (x, y) = self.grid.point(point);
+        let mut out = Vec::with_capacity(4);
+        if x > 0 {
+            out.push(self.grid.index((x - 1, y)));
+        }
+        if x + 1 < self.grid.width {
+            out.push(self.grid.index((x + 1, y)));
+        }
+        if y > 0 {
+            out.push(self.grid.index((x, y - 1)));
+        }
+        if y + 1 < self.grid.height {
+            out.push(self.grid.index((x, y + 1)));
+        }
+        out
+    }
+
+    fn position(&self, point: usize) -> (f32, f32) {
+        self.grid.grid_position(point)
+    }
+}
+
+/// Tiles sharing an edge or a corner.
+#[derive(Clone, Copy, Debug)]
+pub struct EightConnected {
+    pub grid: Grid
+}
+
+impl Neighborhood for EightConnected {
+    fn len(&self) -> usize {
+        self.grid.len()
+    }
+
+    fn neighbors(&self, point: usize) -> Vec<usize> {
+        self.grid.window(point, 1)
+    }
+
+    fn position(&self, point: usize) -> (f32, f32) {
+        self.grid.grid_position(point)
+    }
+}
+
+/// An explicit neighbor list for each point, e.g. territories that share a border.
+#[derive(Clone, Debug, Default)]
+pub struct AdjacencyList {
+    neighbors: Vec<Vec<usize>>,
+    positions: Vec<(f32, f32)>
+}
+
+impl AdjacencyList {
+    /// `len` points connected by undirected `edges`. All positions are `(0, 0)`
+    /// until set with `with_positions`.
+    pub fn from_edges(len: usize, edges: &[(usize, usize)]) -> AdjacencyList {
+        let mut neighbors = vec![Vec::new(); len];
+        for &(a, b) in edges {
+            if a != b && !neighbors[a].contains(&b) {
+                neighbors[a].push(b);
+                neighbors[b].push(a);
+            }
+        }
+        AdjacencyList { neighbors, positions: vec![(0.0, 0.0); len] }
+    }
+
+    /// Panics if `positions` does not have one entry per point.
+    pub fn with_positions(mut self, positions: Vec<(f32, f32)>) -> AdjacencyList {
+        assert_eq!(positions.len(), self.neighbors.len(), "one position per point");
+        self.positions = positions;
+        self
+    }
+}
+
+impl Neighborhood for AdjacencyList {
+    fn len(&self) -> usize {
+        self.neighbors.len()
+    }
+
+    fn neighbors(&self, point: usize) -> Vec<usize> {
+        self.neighbors[point].clone()
+    }
+
+    fn position(&self, point: usize) -> (f32, f32) {
+        self.positions[point]
+    }
+}
@@ -0,0 +1,39 @@
+use std::str::FromStr;
+
+/// How a transfer is picked among the candidates that improve the balance.
+/// See "Execution Plans" in the readme.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ExecutionPlan {
+    /// Take the first candidate that improves the balance.
+    #[default]
+    Eager,
+    /// Take the candidate that improves the balance the most.
+    Greedy,
+    /// Take the improving candidate closest to the anchor.
+    Distance(DistanceAnchor)
+}
+
+/// What the Distance plan measures distance to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum DistanceAnchor {
+    /// The boundary tile of the taking group that found the candidate (p1 in the readme).
+    #[default]
+    TransferPoint,
+    /// The centroid of all tiles in the taking group.
+    Centroid
+}
+
+impl FromStr for ExecutionPlan {
+    type Err = String;
+
+    /// Parses `eager`, `greedy`, `distance-point` or `distance-centroid`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "eager" => Ok(ExecutionPlan::Eager),
+            "greedy" => Ok(ExecutionPlan::Greedy),
+            "distance-point" => Ok(ExecutionPlan::Distance(DistanceAnchor::TransferPoint)),
+            "distance-centroid" => Ok(ExecutionPlan::Distance(DistanceAnchor::Centroid)),
+            _ => Err(format!("unknown execution plan '{}'", s))
+        }
+    }
+}
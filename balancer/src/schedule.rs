@@ -0,0 +1,131 @@
+use std::str::FromStr;
+use crate::solver::{Balancer, Instruction, Preview};
+
+/// How a batch of transfers is ordered into a tour for whoever carries them out.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum TourOrder {
+    /// Always go to the nearest transfer that still lowers the balance.
+    #[default]
+    NearestNeighbor,
+    /// The nearest neighbor tour, shortened by 2-opt moves that keep every transfer lowering the balance.
+    TwoOpt
+}
+
+impl FromStr for TourOrder {
+    type Err = String;
+
+    /// Parses `nearest` or `two-opt`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(TourOrder::NearestNeighbor),
+            "two-opt" => Ok(TourOrder::TwoOpt),
+            _ => Err(format!("unknown tour order '{}'", s))
+        }
+    }
+}
+
+/// Up to `size` transfers, each lowering the balance of the map left by the ones before it,
+/// starting from `state` (`Preview::new(&balancer)` for the balancer as it is).
+/// Only points for which `allowed` is true are moved, and each point at most once.
+pub fn plan_batch(state:&Preview, size:usize, allowed:&dyn Fn(usize) -> bool) -> Vec<Instruction> {
+    let mut state = state.clone();
+    let mut batch: Vec<Instruction> = Vec::new();
+    while batch.len() < size {
+        let next = state.next_instruction_where(&|p| allowed(p) && batch.iter().all(|i| i.transfer_point != p));
+        match next {
+            Some(ins) => {
+                state.reassign_group(ins.transfer_point, ins.to_group);
+                batch.push(ins);
+            },
+            None => break
+        }
+    }
+    batch
+}
+
+/// Orders `batch` into a short tour from `start`, by Manhattan distance between point positions.
+/// Carried out on `state` in the returned order, every transfer lowers the balance. Transfers
+/// that no longer do once the others are done are left out.
+pub fn order_tour(state:&Preview, start:(f32, f32), batch:&[Instruction], order:TourOrder) -> Vec<Instruction> {
+    let mut tour = nearest_neighbor_tour(state, start, batch);
+    if order == TourOrder::TwoOpt {
+        two_opt(state, start, &mut tour);
+    }
+    tour
+}
+
+/// Length of the walk from `start` through the points of `tour`.
+pub fn tour_length(balancer:&Balancer, start:(f32, f32), tour:&[Instruction]) -> f32 {
+    let mut here = start;
+    let mut length = 0.0;
+    for ins in tour {
+        let next = balancer.neighborhood().position(ins.transfer_point);
+        length += manhattan(here, next);
+        here = next;
+    }
+    length
+}
+
+fn manhattan(a:(f32, f32), b:(f32, f32)) -> f32 {
+    (a.0 - b.0).abs() + (a.1 - b.1).abs()
+}
+
+/// Whether `ins` moves a tile out of another group and lowers the balance of `state`.
+fn improves(state:&Preview, ins:&Instruction) -> bool {
+    match state.group(ins.transfer_point) {
+        Some(giver) => giver != ins.to_group && state.should_take(ins.transfer_point, giver, ins.to_group),
+        None => false
+    }
+}
+
+fn nearest_neighbor_tour(state:&Preview, start:(f32, f32), batch:&[Instruction]) -> Vec<Instruction> {
+    let balancer = state.balancer();
+    let mut state = state.clone();
+    let mut remaining = batch.to_vec();
+    let mut tour = Vec::with_capacity(batch.len());
+    let mut here = start;
+    loop {
+        let nearest = remaining.iter().enumerate()
+            .filter(|(_, ins)| improves(&state, ins))
+            .map(|(i, ins)| (manhattan(here, balancer.neighborhood().position(ins.transfer_point)), i))
+            .min_by(|a, b| a.0.total_cmp(&b.0));
+        let ins = match nearest {
+            Some((_, i)) => remaining.swap_remove(i),
+            None => break
+        };
+        state.reassign_group(ins.transfer_point, ins.to_group);
+        here = balancer.neighborhood().position(ins.transfer_point);
+        tour.push(ins);
+    }
+    tour
+}
+
+/// Reverses stretches of `tour` while that makes it shorter and every transfer still lowers the balance.
+fn two_opt(state:&Preview, start:(f32, f32), tour:&mut [Instruction]) {
+    let balancer = state.balancer();
+    let keeps_improving = |tour:&[Instruction]| {
+        let mut state = state.clone();
+        tour.iter().all(|ins| {
+            let ok = improves(&state, ins);
+            state.reassign_group(ins.transfer_point, ins.to_group);
+            ok
+        })
+    };
+    let mut length = tour_length(balancer, start, tour);
+    let mut improved = true;
+    while improved {
+        improved = false;
+        for i in 0..tour.len() {
+            for j in i + 1..tour.len() {
+                tour[i..=j].reverse();
+                let new_length = tour_length(balancer, start, tour);
+                if new_length < length && keeps_improving(tour) {
+                    length = new_length;
+                    improved = true;
+                } else {
+                    tour[i..=j].reverse();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,722 @@
+use std::{borrow::Cow, collections::{BTreeSet, HashMap, HashSet, VecDeque}, sync::Arc};
+use itertools::Either;
+use crate::measure::{BalanceMeasure, MeanPairwiseDifference};
+use crate::neighborhood::Neighborhood;
+use crate::plan::{DistanceAnchor, ExecutionPlan};
+use crate::stop::{RunTracker, SolverStatus, StopCriteria};
+
+/// What is on a point of the map.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Cell {
+    /// Not part of any group and never will be.
+    Wall,
+    /// Nothing to balance here.
+    Empty,
+    /// A point of the given group.
+    Group(usize)
+}
+
+impl Cell {
+    pub fn group(self) -> Option<usize> {
+        match self {
+            Cell::Group(g) => Some(g),
+            _ => None
+        }
+    }
+}
+
+/// Move `transfer_point` into `to_group`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Instruction {
+    pub transfer_point: usize,
+    pub to_group: usize
+}
+
+/// Mean pairwise absolute difference of the group sums. See "Balance Measure" in the readme.
+/// Zero when there are fewer than two groups.
+pub fn calc_balance(data:&[u32]) -> f32 {
+    MeanPairwiseDifference.measure(&to_f64(data))
+}
+
+fn to_f64(data:&[u32]) -> Vec<f64> {
+    data.iter().map(|&v| v as f64).collect()
+}
+
+/// The state of the balancing algorithm.
+///
+/// Points are the indices of the neighborhood. `tile_groups[p]` is what is on point `p`
+/// and `values(p)` are its values, one per attribute. Groups are numbered `0..group_count`.
+///
+/// With several attributes, the balance is the weighted sum of the balance measure of
+/// each attribute's group sums.
+///
+/// Groups can have different capacities: the balancer then drives group sums toward
+/// proportions of the capacities rather than toward equal sums.
+#[derive(Clone, Debug)]
+pub struct Balancer {
+    tile_groups: Vec<Cell>,
+    /// The groups the balancer was created with.
+    initial_groups: Vec<Cell>,
+    /// `tile_values[p * attribute_count + k]` is attribute `k` of point `p`.
+    tile_values: Vec<u32>,
+    attribute_count: usize,
+    weights: Vec<f32>,
+    capacities: Vec<f32>,
+    /// `mean capacity / capacities[g]`, what group sums are multiplied by before measuring.
+    capacity_scale: Vec<f64>,
+    /// `group_sum[k][g]` is the sum of attribute `k` over group `g`.
+    group_sum: Vec<Vec<u32>>,
+    group_balance: f32,
+    plan: ExecutionPlan,
+    measure: Arc<dyn BalanceMeasure>,
+    /// Reject transfers that would split the giving group.
+    preserve_contiguity: bool,
+    neighborhood: Arc<dyn Neighborhood>,
+    /// For every point, its neighbors in other groups.
+    tile_neighbors: Vec<Vec<usize>>,
+    /// For every group, its points with a neighbor in another group. Ordered, so that
+    /// candidates are always tried in the same order.
+    boundary: Vec<BTreeSet<usize>>,
+    group_coord_sum: Vec<(f64, f64)>,
+    group_tile_count: Vec<usize>
+}
+
+impl Balancer {
+
+    /// `tile_groups` and `tile_values` must have one entry per point of `neighborhood`,
+    /// and every group in `tile_groups` must be below `group_count`.
+    pub fn new(tile_groups:Vec<Cell>, tile_values:Vec<u32>, group_count:usize
+        , neighborhood:impl Neighborhood + 'static) -> Balancer
+    {
+        Balancer::with_attributes(tile_groups, tile_values, 1, group_count, neighborhood)
+    }
+
+    /// Like `new`, with `attribute_count` values per point: `tile_values[p * attribute_count + k]`
+    /// is attribute `k` of point `p`. All weights start at 1.
+    pub fn with_attributes(tile_groups:Vec<Cell>, tile_values:Vec<u32>, attribute_count:usize
+        , group_count:usize, neighborhood:impl Neighborhood + 'static) -> Balancer
+    {
+        assert!(attribute_count > 0, "at least one attribute");
+        assert_eq!(tile_groups.len(), neighborhood.len(), "one group per point");
+        assert_eq!(tile_values.len(), neighborhood.len() * attribute_count, "attribute_count values per point");
+        let mut group_sum = vec![vec![0; group_count]; attribute_count];
+        for (&cell, values) in tile_groups.iter().zip(tile_values.chunks(attribute_count)) {
+            if let Cell::Group(g) = cell {
+                assert!(g < group_count, "group {} is out of range, there are {} groups", g, group_count);
+                for (k, &v) in values.iter().enumerate() {
+                    group_sum[k][g] += v;
+                }
+            }
+        }
+        let mut balancer = Balancer {
+            tile_neighbors: vec![Vec::new(); tile_groups.len()],
+            boundary: vec![BTreeSet::new(); group_count],
+            initial_groups: tile_groups.clone(),
+            tile_groups,
+            tile_values,
+            attribute_count,
+            weights: vec![1.0; attribute_count],
+            capacities: vec![1.0; group_count],
+            capacity_scale: vec![1.0; group_count],
+            group_sum,
+            group_balance: 0.0,
+            plan: ExecutionPlan::default(),
+            measure: Arc::new(MeanPairwiseDifference),
+            preserve_contiguity: false,
+            neighborhood: Arc::new(neighborhood),
+            group_coord_sum: vec![(0.0, 0.0); group_count],
+            group_tile_count: vec![0; group_count]
+        };
+        balancer.calculate_neighbors();
+        balancer.calculate_centroids();
+        balancer.update_balance();
+        balancer
+    }
+
+    /// Number of points.
+    pub fn len(&self) -> usize {
+        self.tile_groups.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tile_groups.is_empty()
+    }
+
+    /// Number of groups.
+    pub fn group_count(&self) -> usize {
+        self.group_sum[0].len()
+    }
+
+    /// Number of values per point.
+    pub fn attribute_count(&self) -> usize {
+        self.attribute_count
+    }
+
+    pub fn cell(&self, point:usize) -> Cell {
+        self.tile_groups[point]
+    }
+
+    /// The group of `point`, `None` for empty and wall tiles.
+    pub fn group(&self, point:usize) -> Option<usize> {
+        self.tile_groups[point].group()
+    }
+
+    /// The first attribute of `point`.
+    pub fn value(&self, point:usize) -> u32 {
+        self.tile_values[point * self.attribute_count]
+    }
+
+    /// All attributes of `point`.
+    pub fn values(&self, point:usize) -> &[u32] {
+        &self.tile_values[point * self.attribute_count..(point + 1) * self.attribute_count]
+    }
+
+    pub fn tile_groups(&self) -> &[Cell] {
+        &self.tile_groups
+    }
+
+    /// The group of `point` before any transfer.
+    pub fn initial_group(&self, point:usize) -> Option<usize> {
+        self.initial_groups[point].group()
+    }
+
+    /// Group sums of the first attribute.
+    pub fn group_sum(&self) -> &[u32] {
+        &self.group_sum[0]
+    }
+
+    /// Group sums of attribute `k`.
+    pub fn attribute_sums(&self, k:usize) -> &[u32] {
+        &self.group_sum[k]
+    }
+
+    pub fn weights(&self) -> &[f32] {
+        &self.weights
+    }
+
+    /// One non-negative, finite weight per attribute. The balance is the weighted sum of each
+    /// attribute's balance.
+    pub fn set_weights(&mut self, weights:Vec<f32>) {
+        assert_eq!(weights.len(), self.attribute_count, "one weight per attribute");
+        assert!(weights.iter().all(|&w| w >= 0.0 && w.is_finite()), "weights must be non-negative and finite");
+        self.weights = weights;
+        self.update_balance();
+    }
+
+    pub fn capacities(&self) -> &[f32] {
+        &self.capacities
+    }
+
+    /// One positive, finite capacity per group, e.g. 1 for a full-time rep and 0.5 for a half-time one.
+    /// Group sums are measured relative to their capacity, so the balancer aims for sums in
+    /// proportion to it. All capacities are 1 by default, which is the same as equal sums.
+    pub fn set_capacities(&mut self, capacities:Vec<f32>) {
+        assert_eq!(capacities.len(), self.group_count(), "one capacity per group");
+        assert!(capacities.iter().all(|&c| c > 0.0 && c.is_finite()), "capacities must be positive and finite");
+        let mean = capacities.iter().map(|&c| c as f64).sum::<f64>() / capacities.len() as f64;
+        self.capacity_scale = capacities.iter().map(|&c| mean / c as f64).collect();
+        self.capacities = capacities;
+        self.update_balance();
+    }
+
+    pub fn group_balance(&self) -> f32 {
+        self.group_balance
+    }
+
+    pub fn tile_neighbors(&self) -> &[Vec<usize>] {
+        &self.tile_neighbors
+    }
+
+    /// The points of `group` that have a neighbor in another group, in increasing order.
+    pub fn boundary_points(&self, group:usize) -> impl Iterator<Item = usize> + '_ {
+        self.boundary[group].iter().copied()
+    }
+
+    pub fn neighborhood(&self) -> &dyn Neighborhood {
+        self.neighborhood.as_ref()
+    }
+
+    pub fn plan(&self) -> ExecutionPlan {
+        self.plan
+    }
+
+    pub fn set_plan(&mut self, plan:ExecutionPlan) {
+        self.plan = plan;
+    }
+
+    pub fn preserve_contiguity(&self) -> bool {
+        self.preserve_contiguity
+    }
+
+    /// When on, a transfer is only taken if the giving group stays as connected as it was
+    /// under the neighborhood. Off by default.
+    pub fn set_preserve_contiguity(&mut self, on:bool) {
+        self.preserve_contiguity = on;
+    }
+
+    pub fn measure(&self) -> &dyn BalanceMeasure {
+        self.measure.as_ref()
+    }
+
+    /// Switches the balance measure that `group_balance`, `should_take` and the stopping
+    /// threshold are based on. Panics if `BalanceMeasure::check` rejects the map.
+    pub fn set_measure(&mut self, measure:Box<dyn BalanceMeasure>) {
+        if let Err(e) = measure.check(self.group_count(), self.attribute_count) {
+            panic!("{}", e);
+        }
+        self.measure = Arc::from(measure);
+        self.update_balance();
+    }
+
+    fn update_balance(&mut self) {
+        let sums: Vec<Vec<f64>> = self.group_sum.iter().map(|s| to_f64(s)).collect();
+        self.group_balance = self.combined_balance(&sums);
+    }
+
+    /// Weighted sum of the totals of all attributes, the scale of `Threshold::Total`.
+    pub fn weighted_total(&self) -> f64 {
+        self.group_sum.iter().zip(self.weights.iter())
+            .map(|(s, &w)| w as f64 * s.iter().map(|&v| v as f64).sum::<f64>())
+            .sum()
+    }
+
+    /// Weighted sum of the balance of each attribute's capacity-scaled group sums.
+    fn combined_balance(&self, sums:&[Vec<f64>]) -> f32 {
+        sums.iter().zip(self.weights.iter())
+            .map(|(s, w)| w * self.measure.measure(&self.scaled(s)))
+            .sum()
+    }
+
+    /// Group sums relative to capacity, in the units of an average group. Left as they are
+    /// for a measure that does not use capacities.
+    fn scaled(&self, sums:&[f64]) -> Vec<f64> {
+        if !self.measure.uses_capacities() {
+            return sums.to_vec()
+        }
+        sums.iter().zip(self.capacity_scale.iter()).map(|(v, f)| v * f).collect()
+    }
+
+    /// Neighbors of `point` that belong to a group other than `group`.
+    fn foreign_neighbors(&self, point:usize, group:usize) -> Vec<usize> {
+        self.neighborhood.neighbors(point).into_iter()
+            .filter(|&q| matches!(self.tile_groups[q], Cell::Group(g) if g != group))
+            .collect()
+    }
+
+    fn calculate_neighbors(&mut self) {
+        for point in 0..self.len() {
+            self.refresh_neighbors(point);
+        }
+    }
+
+    /// Recomputes the foreign neighbors of `point` and whether it is on its group's boundary.
+    fn refresh_neighbors(&mut self, point:usize) {
+        match self.tile_groups[point] {
+            Cell::Group(group) => {
+                self.tile_neighbors[point] = self.foreign_neighbors(point, group);
+                if self.tile_neighbors[point].is_empty() {
+                    self.boundary[group].remove(&point);
+                } else {
+                    self.boundary[group].insert(point);
+                }
+            },
+            _ => self.tile_neighbors[point].clear()
+        }
+    }
+
+    fn calculate_centroids(&mut self) {
+        let group_count = self.group_count();
+        self.group_coord_sum = vec![(0.0, 0.0); group_count];
+        self.group_tile_count = vec![0; group_count];
+        for (point, &cell) in self.tile_groups.iter().enumerate() {
+            if let Cell::Group(g) = cell {
+                let (x, y) = self.neighborhood.position(point);
+                self.group_coord_sum[g].0 += x as f64;
+                self.group_coord_sum[g].1 += y as f64;
+                self.group_tile_count[g] += 1;
+            }
+        }
+    }
+
+    /// Panics if `point` is an empty or wall tile.
+    pub fn reassign_group(&mut self, point:usize, to_group:usize) {
+        let from_group = self.tile_groups[point].group().expect("only tiles of a group can be reassigned");
+        let (x, y) = self.neighborhood.position(point);
+        for k in 0..self.attribute_count {
+            let v = self.tile_values[point * self.attribute_count + k];
+            self.group_sum[k][from_group] -= v;
+            self.group_sum[k][to_group] += v;
+        }
+        self.group_coord_sum[from_group].0 -= x as f64;
+        self.group_coord_sum[from_group].1 -= y as f64;
+        self.group_tile_count[from_group] -= 1;
+        self.group_coord_sum[to_group].0 += x as f64;
+        self.group_coord_sum[to_group].1 += y as f64;
+        self.group_tile_count[to_group] += 1;
+        self.tile_groups[point] = Cell::Group(to_group);
+        self.update_balance();
+        // Only `point` and its neighbors can have gained or lost a foreign neighbor.
+        self.boundary[from_group].remove(&point);
+        self.refresh_neighbors(point);
+        for q in self.neighborhood.neighbors(point) {
+            self.refresh_neighbors(q);
+        }
+    }
+
+    /// The group to grow next: the one most under its target (the smallest one when capacities
+    /// are equal), unless the balance measure says otherwise. With several attributes the measure
+    /// chooses from each group's weighted share of the attribute totals relative to its capacity.
+    /// 0 when there are no groups.
+    pub fn find_min_group(&self) -> usize {
+        Preview::new(self).find_min_group()
+    }
+
+    /// The next transfer to make: into the group from `find_min_group` if it has one that
+    /// improves the balance, otherwise into the first other group that does (step 3b of the
+    /// readme). `None` when no transfer into any group improves the balance, or there are no groups.
+    pub fn next_instruction(&self) -> Option<Instruction> {
+        self.next_instruction_where(&|_| true)
+    }
+
+    /// Like `next_instruction`, only moving points for which `allowed` is true.
+    pub fn next_instruction_where(&self, allowed:&dyn Fn(usize) -> bool) -> Option<Instruction> {
+        Preview::new(self).next_instruction_where(allowed)
+    }
+
+    /// One iteration of the algorithm: find the next transfer and apply it.
+    /// Returns `None`, leaving the map as it is, if no transfer improves the balance.
+    pub fn step(&mut self) -> Option<Instruction> {
+        let ins = self.next_instruction()?;
+        self.reassign_group(ins.transfer_point, ins.to_group);
+        Some(ins)
+    }
+
+    /// Calls `step` until one of `criteria` is met or no transfer improves the balance.
+    /// Returns the number of transfers made and why it stopped.
+    pub fn run(&mut self, criteria:&StopCriteria) -> (usize, SolverStatus) {
+        let mut tracker = RunTracker::new(criteria.clone(), self);
+        let mut transfers = 0;
+        loop {
+            if let Some(status) = tracker.check(self.group_balance, transfers) {
+                return (transfers, status)
+            }
+            if self.step().is_none() {
+                return (transfers, SolverStatus::Stuck)
+            }
+            transfers += 1;
+        }
+    }
+
+    /// A transfer into `group` that lowers the balance, chosen by the current execution plan.
+    pub fn create_instruction(&self, group:usize) -> Option<Instruction>{
+        self.create_instruction_where(group, &|_| true)
+    }
+
+    /// Like `create_instruction`, only moving points for which `allowed` is true.
+    pub fn create_instruction_where(&self, group:usize, allowed:&dyn Fn(usize) -> bool) -> Option<Instruction>{
+        Preview::new(self).create_instruction_where(group, allowed)
+    }
+
+    pub fn should_take(&self, giver_point:usize, giver_group:usize, taker_group:usize) -> bool {
+        Preview::new(self).should_take(giver_point, giver_group, taker_group)
+    }
+
+    /// Whether removing `point` from its group splits the group's other tiles around it
+    /// into more than one connected component.
+    pub fn is_cut_point(&self, point:usize) -> bool {
+        Preview::new(self).is_cut_point(point)
+    }
+
+    /// Number of connected components of each group under the neighborhood.
+    /// A contiguous group has 1, a group without tiles has 0.
+    pub fn component_counts(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.group_count()];
+        let mut seen = vec![false; self.len()];
+        for start in 0..self.len() {
+            let group = match self.tile_groups[start] {
+                Cell::Group(g) if !seen[start] => g,
+                _ => continue
+            };
+            counts[group] += 1;
+            seen[start] = true;
+            let mut queue = VecDeque::from([start]);
+            while let Some(p) = queue.pop_front() {
+                for q in self.neighborhood.neighbors(p) {
+                    if !seen[q] && self.tile_groups[q] == Cell::Group(group) {
+                        seen[q] = true;
+                        queue.push_back(q);
+                    }
+                }
+            }
+        }
+        counts
+    }
+
+    /// The balance we would get if giver_point moved from giver_group to taker_group.
+    pub fn balance_after(&self, giver_point:usize, giver_group:usize, taker_group:usize) -> f32 {
+        Preview::new(self).balance_after(giver_point, giver_group, taker_group)
+    }
+}
+
+/// Transfers tried on top of a `Balancer` without changing it: the moved points with their new
+/// groups, and the group sums and balance they lead to. Neighbor lists are only recomputed
+/// around the moved points, so a preview of a few transfers is cheap to make and to extend.
+/// The balancer itself picks its transfers through an empty preview. Cloning a preview copies
+/// only the moved points and the group sums.
+#[derive(Clone)]
+pub struct Preview<'a> {
+    balancer: &'a Balancer,
+    moved: HashMap<usize, usize>,
+    group_sum: Cow<'a, [Vec<u32>]>,
+    group_balance: f32
+}
+
+impl<'a> Preview<'a> {
+    /// A preview of `balancer` with nothing moved yet.
+    pub fn new(balancer:&'a Balancer) -> Preview<'a> {
+        Preview {
+            balancer,
+            moved: HashMap::new(),
+            group_sum: Cow::Borrowed(&balancer.group_sum),
+            group_balance: balancer.group_balance
+        }
+    }
+
+    pub fn balancer(&self) -> &'a Balancer {
+        self.balancer
+    }
+
+    pub fn cell(&self, point:usize) -> Cell {
+        match self.moved.get(&point) {
+            Some(&group) => Cell::Group(group),
+            None => self.balancer.tile_groups[point]
+        }
+    }
+
+    pub fn group(&self, point:usize) -> Option<usize> {
+        self.cell(point).group()
+    }
+
+    /// Like `Balancer::reassign_group`. Panics if `point` is an empty or wall tile.
+    pub fn reassign_group(&mut self, point:usize, to_group:usize) {
+        let from_group = self.group(point).expect("only tiles of a group can be reassigned");
+        let balancer = self.balancer;
+        let group_sum = self.group_sum.to_mut();
+        for (k, &v) in balancer.values(point).iter().enumerate() {
+            group_sum[k][from_group] -= v;
+            group_sum[k][to_group] += v;
+        }
+        self.moved.insert(point, to_group);
+        let sums: Vec<Vec<f64>> = self.group_sum.iter().map(|s| to_f64(s)).collect();
+        self.group_balance = balancer.combined_balance(&sums);
+    }
+
+    pub fn group_balance(&self) -> f32 {
+        self.group_balance
+    }
+
+    pub(crate) fn find_min_group(&self) -> usize {
+        let balancer = self.balancer;
+        if balancer.attribute_count == 1 {
+            return balancer.measure.neediest_group(&balancer.scaled(&to_f64(&self.group_sum[0])))
+        }
+        let mut score = vec![0.0; balancer.group_count()];
+        for (sums, &w) in self.group_sum.iter().zip(balancer.weights.iter()) {
+            let total: f64 = sums.iter().map(|&v| v as f64).sum();
+            if total > 0.0 {
+                for (g, &v) in sums.iter().enumerate() {
+                    score[g] += w as f64 * v as f64 / total * balancer.capacity_scale[g];
+                }
+            }
+        }
+        balancer.measure.neediest_group(&score)
+    }
+
+    /// Like `Balancer::next_instruction_where`, on the map with the moved points.
+    pub fn next_instruction_where(&self, allowed:&dyn Fn(usize) -> bool) -> Option<Instruction> {
+        if self.balancer.group_count() == 0 {
+            return None
+        }
+        let start_group = self.find_min_group();
+        std::iter::once(start_group)
+            .chain((0..self.balancer.group_count()).filter(|&g| g != start_group))
+            .find_map(|g| self.create_instruction_where(g, allowed))
+    }
+
+    fn create_instruction_where(&self, group:usize, allowed:&dyn Fn(usize) -> bool) -> Option<Instruction>{
+        match self.balancer.plan {
+            ExecutionPlan::Eager => self.eager_instruction(group, allowed),
+            ExecutionPlan::Greedy => self.greedy_instruction(group, allowed),
+            ExecutionPlan::Distance(anchor) => self.distance_instruction(group, anchor, allowed)
+        }
+    }
+
+    /// Boundary points of `group` with their neighbors in other groups, in point order.
+    fn boundary(&self, group:usize) -> impl Iterator<Item = (usize, Cow<'_, [usize]>)> {
+        let balancer = self.balancer;
+        if self.moved.is_empty() {
+            return Either::Left(balancer.boundary[group].iter()
+                .map(move |&point| (point, Cow::Borrowed(&balancer.tile_neighbors[point][..]))))
+        }
+        // Only the moved points and their neighbors can have other foreign neighbors than in the balancer.
+        let touched: BTreeSet<usize> = self.moved.keys()
+            .flat_map(|&point| std::iter::once(point).chain(balancer.neighborhood.neighbors(point)))
+            .collect();
+        let points: BTreeSet<usize> = balancer.boundary[group].iter().chain(touched.iter())
+            .copied()
+            .filter(|&point| self.cell(point) == Cell::Group(group))
+            .collect();
+        Either::Right(points.into_iter().filter_map(move |point| {
+            if !touched.contains(&point) {
+                return Some((point, Cow::Borrowed(&balancer.tile_neighbors[point][..])))
+            }
+            let neighbors: Vec<usize> = balancer.neighborhood.neighbors(point).into_iter()
+                .filter(|&q| matches!(self.cell(q), Cell::Group(g) if g != group))
+                .collect();
+            (!neighbors.is_empty()).then_some((point, Cow::Owned(neighbors)))
+        }))
+    }
+
+    /// The group of a candidate found in a neighbor list.
+    fn giver(&self, candidate:usize) -> usize {
+        self.group(candidate).expect("neighbor lists only hold tiles of a group")
+    }
+
+    fn group_centroid(&self, group:usize) -> (f32, f32) {
+        let balancer = self.balancer;
+        let (mut sx, mut sy) = balancer.group_coord_sum[group];
+        let mut count = balancer.group_tile_count[group] as f64;
+        for (&point, &to_group) in &self.moved {
+            let from_group = balancer.tile_groups[point].group();
+            let sign = match (from_group == Some(group), to_group == group) {
+                (true, false) => -1.0,
+                (false, true) => 1.0,
+                _ => continue
+            };
+            let (x, y) = balancer.neighborhood.position(point);
+            sx += sign * x as f64;
+            sy += sign * y as f64;
+            count += sign;
+        }
+        let count = count.max(1.0);
+        ((sx / count) as f32, (sy / count) as f32)
+    }
+
+    fn eager_instruction(&self, group:usize, allowed:&dyn Fn(usize) -> bool) -> Option<Instruction>{
+        let taker = group;
+        for (_, nbhd) in self.boundary(taker) {
+            for &candidate in nbhd.iter().filter(|&&c| allowed(c)) {
+                let giver = self.giver(candidate);
+                if self.should_take(candidate, giver, taker) {
+                    return Some(Instruction {transfer_point: candidate, to_group: taker})
+                }
+            }
+        }
+        None
+    }
+
+    fn greedy_instruction(&self, group:usize, allowed:&dyn Fn(usize) -> bool) -> Option<Instruction>{
+        let taker = group;
+        let mut best: Option<(f32, usize)> = None;
+        for (_, nbhd) in self.boundary(taker) {
+            for &candidate in nbhd.iter().filter(|&&c| allowed(c)) {
+                let giver = self.giver(candidate);
+                let new_balance = self.balance_after(candidate, giver, taker);
+                let is_better = match best {
+                    Some((b, _)) => new_balance < b,
+                    None => new_balance < self.group_balance
+                };
+                if is_better && self.keeps_contiguity(candidate) {
+                    best = Some((new_balance, candidate));
+                }
+            }
+        }
+        best.map(|(_, point)| Instruction {transfer_point: point, to_group: taker})
+    }
+
+    fn distance_instruction(&self, group:usize, anchor:DistanceAnchor, allowed:&dyn Fn(usize) -> bool)
+        -> Option<Instruction>
+    {
+        let taker = group;
+        let centroid = self.group_centroid(group);
+        // (distance, balance after transfer, point), ties on distance go to the better balance.
+        let mut best: Option<(f32, f32, usize)> = None;
+        for (key, nbhd) in self.boundary(taker) {
+            let (ax, ay) = match anchor {
+                DistanceAnchor::TransferPoint => self.balancer.neighborhood.position(key),
+                DistanceAnchor::Centroid => centroid
+            };
+            for &candidate in nbhd.iter().filter(|&&c| allowed(c)) {
+                let giver = self.giver(candidate);
+                let new_balance = self.balance_after(candidate, giver, taker);
+                if new_balance >= self.group_balance {
+                    continue
+                }
+                let (x, y) = self.balancer.neighborhood.position(candidate);
+                let dist = (x - ax).abs() + (y - ay).abs();
+                let is_better = match best {
+                    Some((d, b, _)) => dist < d || (dist == d && new_balance < b),
+                    None => true
+                };
+                if is_better && self.keeps_contiguity(candidate) {
+                    best = Some((dist, new_balance, candidate));
+                }
+            }
+        }
+        best.map(|(_, _, point)| Instruction {transfer_point: point, to_group: taker})
+    }
+
+    pub(crate) fn should_take(&self, giver_point:usize, giver_group:usize, taker_group:usize) -> bool {
+        self.balance_after(giver_point, giver_group, taker_group) < self.group_balance
+            && self.keeps_contiguity(giver_point)
+    }
+
+    /// False if contiguity is preserved and taking `point` away would disconnect its group.
+    fn keeps_contiguity(&self, point:usize) -> bool {
+        !self.balancer.preserve_contiguity || !self.is_cut_point(point)
+    }
+
+    /// Whether removing `point` from its group splits the group's other tiles around it
+    /// into more than one connected component.
+    pub(crate) fn is_cut_point(&self, point:usize) -> bool {
+        let group = self.cell(point);
+        let same: Vec<usize> = self.balancer.neighborhood.neighbors(point).into_iter()
+            .filter(|&q| self.cell(q) == group)
+            .collect();
+        if same.len() < 2 {
+            return false
+        }
+        // Search from one same-group neighbor, without going through `point`,
+        // until all the others are found.
+        // A set rather than one flag per point: the search usually ends long before it
+        // has seen the whole map.
+        let mut seen = HashSet::from([point, same[0]]);
+        let mut left = same.len() - 1;
+        let mut queue = VecDeque::from([same[0]]);
+        while let Some(p) = queue.pop_front() {
+            for q in self.balancer.neighborhood.neighbors(p) {
+                if self.cell(q) == group && seen.insert(q) {
+                    if same.contains(&q) {
+                        left -= 1;
+                        if left == 0 {
+                            return false
+                        }
+                    }
+                    queue.push_back(q);
+                }
+            }
+        }
+        true
+    }
+
+    fn balance_after(&self, giver_point:usize, giver_group:usize, taker_group:usize) -> f32 {
+        let mut temp_group_sum:Vec<Vec<f64>> = self.group_sum.iter().map(|s| to_f64(s)).collect();
+        for (k, &v) in self.balancer.values(giver_point).iter().enumerate() {
+            let value_transfered = v as f64;
+            temp_group_sum[k][giver_group] -= value_transfered;
+            temp_group_sum[k][taker_group] += value_transfered;
+        }
+
+        self.balancer.combined_balance(&temp_group_sum)
+    }
+}
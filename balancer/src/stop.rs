@@ -0,0 +1,173 @@
+use std::{collections::VecDeque, fmt, str::FromStr, time::{Duration, Instant}};
+use crate::solver::Balancer;
+
+/// The default threshold of measures in the units of the values, like the mean pairwise difference.
+pub const DEFAULT_THRESHOLD:f32 = 1500.0;
+
+/// The balance below which a run has converged.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Threshold {
+    /// In the units of the balance measure.
+    Absolute(f32),
+    /// A fraction of the (weighted) total value of all groups.
+    Total(f32),
+    /// A fraction of the mean (weighted) group sum.
+    Mean(f32)
+}
+
+impl Default for Threshold {
+    fn default() -> Self {
+        Threshold::Absolute(DEFAULT_THRESHOLD)
+    }
+}
+
+impl Threshold {
+    /// The absolute threshold on the map of `balancer`. The relative ones only make sense
+    /// for measures in the units of the values, not for the coefficient of variation.
+    pub fn resolve(&self, balancer:&Balancer) -> f32 {
+        match *self {
+            Threshold::Absolute(t) => t,
+            Threshold::Total(f) => (f as f64 * balancer.weighted_total()) as f32,
+            Threshold::Mean(f) => (f as f64 * balancer.weighted_total() / balancer.group_count().max(1) as f64) as f32
+        }
+    }
+}
+
+impl FromStr for Threshold {
+    type Err = String;
+
+    /// Parses `<T>` (absolute), `total:<F>` or `mean:<F>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |v: &str| v.trim().parse::<f32>().map_err(|e| format!("threshold '{}': {}", s, e));
+        match s.split_once(':') {
+            None => parse(s).map(Threshold::Absolute),
+            Some(("total", f)) => parse(f).map(Threshold::Total),
+            Some(("mean", f)) => parse(f).map(Threshold::Mean),
+            Some(_) => Err(format!("unknown threshold '{}'", s))
+        }
+    }
+}
+
+/// When a run stops. The threshold always applies, the other criteria only when set.
+#[derive(Clone, PartialEq, Debug)]
+pub struct StopCriteria {
+    /// `None` for the `default_threshold` of the balance measure in use.
+    pub threshold: Option<Threshold>,
+    pub max_transfers: Option<usize>,
+    /// Wall-clock time from the start of the run.
+    pub time_limit: Option<Duration>,
+    /// Stop when the last `n` transfers together lowered the balance by less than
+    /// `min_improvement` times what it was `n` transfers ago.
+    pub no_improvement: Option<usize>,
+    pub min_improvement: f32
+}
+
+impl Default for StopCriteria {
+    fn default() -> Self {
+        StopCriteria {
+            threshold: None, max_transfers: None, time_limit: None,
+            no_improvement: None, min_improvement: 0.001
+        }
+    }
+}
+
+/// How a run of the solver ended.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SolverStatus {
+    /// The balance is below the threshold.
+    Converged,
+    /// No transfer into any group improves the balance: a local optimum.
+    Stuck,
+    /// The maximum number of transfers was reached first.
+    IterationCap,
+    TimeLimit,
+    /// The last transfers did not improve the balance enough.
+    NoImprovement
+}
+
+impl SolverStatus {
+    /// Exit code of a headless run that ended this way: 0 when converged, 3 when stuck,
+    /// 4 at the transfer cap, 5 at the time limit and 6 without improvement.
+    /// 1 and 2 are left for errors and usage errors.
+    pub fn exit_code(self) -> u8 {
+        match self {
+            SolverStatus::Converged => 0,
+            SolverStatus::Stuck => 3,
+            SolverStatus::IterationCap => 4,
+            SolverStatus::TimeLimit => 5,
+            SolverStatus::NoImprovement => 6
+        }
+    }
+}
+
+impl fmt::Display for SolverStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SolverStatus::Converged => "converged",
+            SolverStatus::Stuck => "stuck at a local optimum",
+            SolverStatus::IterationCap => "transfer cap reached",
+            SolverStatus::TimeLimit => "time limit reached",
+            SolverStatus::NoImprovement => "no more improvement"
+        })
+    }
+}
+
+/// Checks a run against its `StopCriteria`, one transfer at a time.
+#[derive(Clone, Debug)]
+pub struct RunTracker {
+    criteria: StopCriteria,
+    /// The threshold resolved on the map and measure at the start of the run.
+    threshold: f32,
+    started: Instant,
+    /// The balance after each of the last `no_improvement` transfers, and before them.
+    recent: VecDeque<f32>,
+    last_transfers: Option<usize>
+}
+
+impl RunTracker {
+    pub fn new(criteria:StopCriteria, balancer:&Balancer) -> RunTracker {
+        RunTracker {
+            threshold: criteria.threshold.unwrap_or_else(|| balancer.measure().default_threshold()).resolve(balancer),
+            criteria,
+            started: Instant::now(),
+            recent: VecDeque::new(),
+            last_transfers: None
+        }
+    }
+
+    pub fn criteria(&self) -> &StopCriteria {
+        &self.criteria
+    }
+
+    /// The absolute threshold of this run.
+    pub fn threshold(&self) -> f32 {
+        self.threshold
+    }
+
+    /// Whether the run should stop now, at `balance` and `transfers` transfers in. Call it before
+    /// every transfer.
+    pub fn check(&mut self, balance:f32, transfers:usize) -> Option<SolverStatus> {
+        if self.last_transfers != Some(transfers) {
+            self.last_transfers = Some(transfers);
+            self.recent.push_back(balance);
+            if self.recent.len() > self.criteria.no_improvement.map_or(0, |n| n + 1) {
+                self.recent.pop_front();
+            }
+        }
+        if balance < self.threshold {
+            return Some(SolverStatus::Converged)
+        }
+        if self.criteria.max_transfers.is_some_and(|max| transfers >= max) {
+            return Some(SolverStatus::IterationCap)
+        }
+        if self.criteria.time_limit.is_some_and(|limit| self.started.elapsed() >= limit) {
+            return Some(SolverStatus::TimeLimit)
+        }
+        if let (Some(n), Some(&before)) = (self.criteria.no_improvement, self.recent.front()) {
+            if self.recent.len() == n + 1 && before - balance <= self.criteria.min_improvement * before {
+                return Some(SolverStatus::NoImprovement)
+            }
+        }
+        None
+    }
+}
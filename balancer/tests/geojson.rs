@@ -0,0 +1,136 @@
+//! `read_geojson`: properties, positions, and which features come out as neighbors.
+
+use balancer::{
+    geojson::{read_geojson, GeoJsonError, GeoMap}, Balancer, Cell, Neighborhood, StopCriteria, Threshold
+};
+use serde_json::json;
+
+/// A Polygon feature with a single ring through `corners`, closed back to the first one.
+fn feature(corners: &[(f64, f64)], group: Option<u64>, value: f64) -> serde_json::Value {
+    let mut ring: Vec<[f64; 2]> = corners.iter().map(|&(x, y)| [x, y]).collect();
+    ring.push(ring[0]);
+    json!({
+        "type": "Feature",
+        "properties": { "group": group, "value": value },
+        "geometry": { "type": "Polygon", "coordinates": [ring] }
+    })
+}
+
+fn read(features: Vec<serde_json::Value>) -> Result<GeoMap, GeoJsonError> {
+    let collection = json!({ "type": "FeatureCollection", "features": features });
+    read_geojson(collection.to_string().as_bytes(), &["value"], "group")
+}
+
+fn neighbors(map: &GeoMap, feature: usize) -> Vec<usize> {
+    let mut neighbors = map.neighborhood.neighbors(feature);
+    neighbors.sort();
+    neighbors
+}
+
+fn square(x: f64, y: f64) -> Vec<(f64, f64)> {
+    vec![(x, y), (x + 1.0, y), (x + 1.0, y + 1.0), (x, y + 1.0)]
+}
+
+#[test]
+fn a_shared_edge_makes_neighbors() {
+    // the second ring runs the other way around
+    let mut left = square(1.0, 0.0);
+    left.reverse();
+    let map = read(vec![feature(&square(0.0, 0.0), Some(0), 1.0), feature(&left, Some(1), 1.0)]).unwrap();
+    assert_eq!(neighbors(&map, 0), vec![1]);
+    assert_eq!(neighbors(&map, 1), vec![0]);
+}
+
+#[test]
+fn a_long_edge_touches_every_shorter_edge_along_it() {
+    // a 2-wide polygon under two 1-wide ones, with no vertex where they meet on its top edge
+    let wide = vec![(0.0, 0.0), (2.0, 0.0), (2.0, 1.0), (0.0, 1.0)];
+    let map = read(vec![
+        feature(&wide, Some(0), 1.0), feature(&square(0.0, 1.0), Some(1), 1.0), feature(&square(1.0, 1.0), Some(1), 1.0)
+    ]).unwrap();
+    assert_eq!(neighbors(&map, 0), vec![1, 2]);
+    assert_eq!(neighbors(&map, 1), vec![0, 2]);
+    assert_eq!(neighbors(&map, 2), vec![0, 1]);
+}
+
+#[test]
+fn partly_overlapping_edges_make_neighbors() {
+    let map = read(vec![feature(&square(0.0, 0.0), Some(0), 1.0), feature(&square(1.0, 0.5), Some(1), 1.0)]).unwrap();
+    assert_eq!(neighbors(&map, 0), vec![1]);
+}
+
+#[test]
+fn corners_and_separate_edges_on_one_line_are_not_neighbors() {
+    let map = read(vec![
+        feature(&square(0.0, 0.0), Some(0), 1.0),
+        // meets the first one at (1, 1) only
+        feature(&square(1.0, 1.0), Some(1), 1.0),
+        // its bottom edge is on the same line as the first one's, further along
+        feature(&square(3.0, 0.0), Some(1), 1.0)
+    ]).unwrap();
+    for feature in 0..3 {
+        assert!(neighbors(&map, feature).is_empty(), "feature {} has neighbors", feature);
+    }
+}
+
+#[test]
+fn a_multipolygon_has_the_neighbors_of_all_its_parts() {
+    let parts = json!([[[[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0], [0.0, 0.0]]],
+        [[[5.0, 0.0], [6.0, 0.0], [6.0, 1.0], [5.0, 1.0], [5.0, 0.0]]]]);
+    let multi = json!({
+        "type": "Feature",
+        "properties": { "group": 0, "value": 1 },
+        "geometry": { "type": "MultiPolygon", "coordinates": parts }
+    });
+    let map = read(vec![multi, feature(&square(-1.0, 0.0), Some(1), 1.0), feature(&square(6.0, 0.0), Some(1), 1.0)])
+        .unwrap();
+    assert_eq!(neighbors(&map, 0), vec![1, 2]);
+}
+
+#[test]
+fn properties_and_positions() {
+    let map = read(vec![
+        feature(&square(0.0, 0.0), Some(2), 4.4),
+        feature(&square(1.0, 0.0), None, 7.0)
+    ]).unwrap();
+    assert_eq!(map.tile_groups, vec![Cell::Group(2), Cell::Empty]);
+    assert_eq!(map.tile_values, vec![4, 7]);
+    assert_eq!((map.attribute_count, map.group_count), (1, 3));
+    assert_eq!(map.neighborhood.position(0), (0.5, 0.5));
+    assert_eq!(map.neighborhood.position(1), (1.5, 0.5));
+}
+
+#[test]
+fn bad_input_is_an_error() {
+    let not_a_collection = json!({ "type": "Feature" }).to_string();
+    assert!(matches!(read_geojson(not_a_collection.as_bytes(), &["value"], "group"),
+        Err(GeoJsonError::NotFeatureCollection)));
+    assert!(matches!(read(Vec::new()), Err(GeoJsonError::Empty)));
+    assert!(matches!(read(vec![feature(&square(0.0, 0.0), Some(0), -1.0)]),
+        Err(GeoJsonError::Property { feature: 0, .. })));
+    let huge_group = vec![feature(&square(0.0, 0.0), Some(0), 1.0), feature(&square(1.0, 0.0), Some(3_000_000_000), 1.0)];
+    assert!(matches!(read(huge_group), Err(GeoJsonError::Property { feature: 1, .. })));
+    let point = json!({
+        "type": "Feature",
+        "properties": { "group": 0, "value": 1 },
+        "geometry": { "type": "Point", "coordinates": [0.0, 0.0] }
+    });
+    assert!(matches!(read(vec![point]), Err(GeoJsonError::Geometry { feature: 0 })));
+}
+
+#[test]
+fn features_across_a_long_edge_are_transferred() {
+    // group 1 only reaches the wide feature through the middle of its top edge, and taking
+    // the other feature of group 0 would make the balance worse
+    let wide = vec![(0.0, 0.0), (2.0, 0.0), (2.0, 1.0), (0.0, 1.0)];
+    let map = read(vec![
+        feature(&wide, Some(0), 2.0), feature(&square(0.0, 1.0), Some(0), 10.0), feature(&square(1.0, 1.0), Some(1), 3.0)
+    ]).unwrap();
+    let mut balancer = Balancer::with_attributes(map.tile_groups, map.tile_values, map.attribute_count
+        , map.group_count, map.neighborhood);
+    let before = balancer.group_balance();
+    let (transfers, _) = balancer.run(&StopCriteria { threshold: Some(Threshold::Absolute(0.5)), ..StopCriteria::default() });
+    assert!(transfers > 0);
+    assert_eq!(balancer.group(0), Some(1));
+    assert!(balancer.group_balance() < before);
+}
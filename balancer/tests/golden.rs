@@ -0,0 +1,90 @@
+//! Regression tests: balance the maps in `tests/data` and compare the result with the
+//! files in `tests/golden`. After an intended change of behavior, run with
+//! `UPDATE_GOLDEN=1` to rewrite the golden files, and review the diff.
+
+use std::{fs, path::Path};
+use balancer::{
+    io::{read_map_file, write_grid}, Balancer, DistanceAnchor, ExecutionPlan, FourConnected, RadiusNeighborhood,
+    StopCriteria, Threshold
+};
+
+/// Balances `map` with `configure` applied, and checks the map and summary against `golden`.
+/// Returns the balanced map for further checks.
+fn check(map: &str, golden: &str, configure: impl FnOnce(&mut Balancer), four_connected: bool) -> Balancer {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let map = read_map_file(&dir.join("data").join(map)).expect("test map");
+    let grid = map.grid;
+    let (groups, values, k, n) = (map.tile_groups, map.tile_values, map.attribute_count, map.group_count);
+    let mut balancer = if four_connected {
+        Balancer::with_attributes(groups, values, k, n, FourConnected { grid })
+    } else {
+        Balancer::with_attributes(groups, values, k, n, RadiusNeighborhood { grid, radius: 2 })
+    };
+    configure(&mut balancer);
+    let initial_balance = balancer.group_balance();
+    let (transfers, status) = balancer.run(&StopCriteria { threshold: Some(Threshold::Absolute(1.0)), ..StopCriteria::default() });
+
+    let mut out = Vec::new();
+    write_grid(&mut out, &grid, &balancer).unwrap();
+    let mut out = String::from_utf8(out).unwrap();
+    out += &format!("// status: {}\n// transfers: {}\n", status, transfers);
+    out += &format!("// group_balance: {} -> {}\n", initial_balance, balancer.group_balance());
+    for kth in 0..balancer.attribute_count() {
+        out += &format!("// group sums {}: {:?}\n", kth, balancer.attribute_sums(kth));
+    }
+    out += &format!("// components: {:?}\n", balancer.component_counts());
+
+    let path = dir.join("golden").join(golden);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&path, &out).unwrap();
+    }
+    let expected = fs::read_to_string(&path).unwrap_or_default();
+    assert!(out == expected, "{} differs from the golden file:\n{}", golden, out);
+    balancer
+}
+
+#[test]
+fn eager() {
+    check("three_groups.txt", "eager.txt", |_| {}, false);
+}
+
+#[test]
+fn greedy() {
+    check("three_groups.txt", "greedy.txt", |b| b.set_plan(ExecutionPlan::Greedy), false);
+}
+
+#[test]
+fn distance_to_transfer_point() {
+    check("three_groups.txt", "distance_point.txt",
+        |b| b.set_plan(ExecutionPlan::Distance(DistanceAnchor::TransferPoint)), false);
+}
+
+#[test]
+fn distance_to_centroid() {
+    check("three_groups.txt", "distance_centroid.txt",
+        |b| b.set_plan(ExecutionPlan::Distance(DistanceAnchor::Centroid)), false);
+}
+
+#[test]
+fn contiguous_four_connected() {
+    // Every group starts as one piece, and the heavy group reaches around the middle one through a
+    // thin arm that a careless transfer would cut.
+    let balancer = check("connected_groups.txt", "contiguous.txt", |b| {
+        assert_eq!(b.component_counts(), vec![1, 1, 1]);
+        b.set_preserve_contiguity(true);
+    }, true);
+    assert_eq!(balancer.component_counts(), vec![1, 1, 1]);
+}
+
+#[test]
+fn capacities() {
+    check("three_groups.txt", "capacities.txt", |b| b.set_capacities(vec![1.0, 0.5, 1.0]), false);
+}
+
+#[test]
+fn weighted_attributes() {
+    check("two_attributes.txt", "weighted_attributes.txt", |b| {
+        b.set_plan(ExecutionPlan::Greedy);
+        b.set_weights(vec![1.0, 2.0]);
+    }, false);
+}
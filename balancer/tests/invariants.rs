@@ -0,0 +1,211 @@
+//! Invariants the algorithm relies on, checked on random maps after every transfer.
+
+use balancer::{
+    order_tour, plan_batch, tour_length, BalanceMeasure, Balancer, Cell, DistanceAnchor, ExecutionPlan, Grid,
+    Instruction, Preview, RadiusNeighborhood, TargetDistance, TourOrder
+};
+use proptest::prelude::*;
+
+/// A random map: grid side, group count, radius, cells and values.
+fn map() -> impl Strategy<Value = (usize, usize, usize, Vec<Cell>, Vec<u32>)> {
+    (4usize..16, 2usize..5, 1usize..3).prop_flat_map(|(side, group_count, radius)| {
+        let cell = prop_oneof![
+            1 => Just(Cell::Wall),
+            3 => Just(Cell::Empty),
+            4 => (0..group_count).prop_map(Cell::Group)
+        ];
+        (
+            Just(side), Just(group_count), Just(radius),
+            prop::collection::vec(cell, side * side),
+            prop::collection::vec(1u32..100, side * side)
+        )
+    })
+}
+
+fn plan() -> impl Strategy<Value = ExecutionPlan> {
+    prop_oneof![
+        Just(ExecutionPlan::Eager),
+        Just(ExecutionPlan::Greedy),
+        Just(ExecutionPlan::Distance(DistanceAnchor::TransferPoint)),
+        Just(ExecutionPlan::Distance(DistanceAnchor::Centroid))
+    ]
+}
+
+fn balancer(side: usize, group_count: usize, radius: usize, cells: Vec<Cell>, values: Vec<u32>) -> Balancer {
+    Balancer::new(cells, values, group_count, RadiusNeighborhood { grid: Grid::new(side, side), radius })
+}
+
+/// `group_sum` recomputed from the tiles.
+fn sums_from_tiles(balancer: &Balancer) -> Vec<u32> {
+    let mut sums = vec![0; balancer.group_count()];
+    for point in 0..balancer.len() {
+        if let Some(g) = balancer.group(point) {
+            sums[g] += balancer.value(point);
+        }
+    }
+    sums
+}
+
+proptest! {
+    #[test]
+    fn transfers_keep_totals_and_sums((side, group_count, radius, cells, values) in map(), plan in plan()) {
+        let mut balancer = balancer(side, group_count, radius, cells, values);
+        balancer.set_plan(plan);
+        let total: u32 = balancer.group_sum().iter().sum();
+        prop_assert_eq!(balancer.group_sum(), &sums_from_tiles(&balancer)[..]);
+        while balancer.step().is_some() {
+            prop_assert_eq!(balancer.group_sum().iter().sum::<u32>(), total);
+            prop_assert_eq!(balancer.group_sum(), &sums_from_tiles(&balancer)[..]);
+        }
+    }
+
+    #[test]
+    fn every_transfer_lowers_the_balance((side, group_count, radius, cells, values) in map(), plan in plan()) {
+        let mut balancer = balancer(side, group_count, radius, cells, values);
+        balancer.set_plan(plan);
+        let mut before = balancer.group_balance();
+        while balancer.step().is_some() {
+            prop_assert!(balancer.group_balance() < before);
+            before = balancer.group_balance();
+        }
+    }
+
+    #[test]
+    fn walls_and_empty_tiles_are_never_assigned((side, group_count, radius, cells, values) in map()) {
+        let mut balancer = balancer(side, group_count, radius, cells.clone(), values);
+        while let Some(ins) = balancer.step() {
+            prop_assert!(cells[ins.transfer_point].group().is_some());
+        }
+        for (point, cell) in cells.iter().enumerate() {
+            if cell.group().is_none() {
+                prop_assert_eq!(balancer.cell(point), *cell);
+            }
+        }
+    }
+
+    #[test]
+    fn neighbor_lists_only_hold_other_groups((side, group_count, radius, cells, values) in map(), plan in plan()) {
+        let mut balancer = balancer(side, group_count, radius, cells, values);
+        balancer.set_plan(plan);
+        loop {
+            for point in 0..balancer.len() {
+                let expected: Vec<usize> = match balancer.group(point) {
+                    Some(g) => balancer.neighborhood().neighbors(point).into_iter()
+                        .filter(|&q| balancer.group(q).is_some_and(|h| h != g))
+                        .collect(),
+                    None => Vec::new()
+                };
+                prop_assert_eq!(&balancer.tile_neighbors()[point], &expected);
+                if let Some(g) = balancer.group(point) {
+                    prop_assert_eq!(balancer.boundary_points(g).any(|q| q == point), !expected.is_empty());
+                }
+            }
+            if balancer.step().is_none() {
+                break
+            }
+        }
+    }
+
+    #[test]
+    fn contiguity_never_adds_components((side, group_count, radius, cells, values) in map()) {
+        let mut balancer = balancer(side, group_count, radius, cells, values);
+        balancer.set_preserve_contiguity(true);
+        let mut components = balancer.component_counts();
+        while let Some(ins) = balancer.step() {
+            let after = balancer.component_counts();
+            for g in 0..group_count {
+                // the taker can only merge pieces, the giver keeps its shape minus one tile
+                if g != ins.to_group {
+                    prop_assert!(after[g] <= components[g]);
+                }
+            }
+            components = after;
+        }
+    }
+
+    #[test]
+    fn batches_are_the_transfers_the_solver_would_make(
+        (side, group_count, radius, cells, values) in map(), plan in plan(), contiguous in any::<bool>(),
+        size in 1usize..10
+    ) {
+        let mut balancer = balancer(side, group_count, radius, cells, values);
+        balancer.set_plan(plan);
+        balancer.set_preserve_contiguity(contiguous);
+        let batch = plan_batch(&Preview::new(&balancer), size, &|_| true);
+        // the same batch, planned on a copy of the balancer that the transfers are applied to
+        let mut state = balancer.clone();
+        let mut expected = Vec::new();
+        while expected.len() < size {
+            let moved: Vec<usize> = expected.iter().map(|i: &Instruction| i.transfer_point).collect();
+            match state.next_instruction_where(&|p| !moved.contains(&p)) {
+                Some(ins) => {
+                    state.reassign_group(ins.transfer_point, ins.to_group);
+                    expected.push(ins);
+                },
+                None => break
+            }
+        }
+        prop_assert_eq!(batch, expected);
+    }
+
+    #[test]
+    fn tours_lower_the_balance_at_every_step(
+        (side, group_count, radius, cells, values) in map(), plan in plan(), size in 1usize..10,
+        start in (0.0f32..16.0, 0.0f32..16.0)
+    ) {
+        let mut balancer = balancer(side, group_count, radius, cells, values);
+        balancer.set_plan(plan);
+        let batch = plan_batch(&Preview::new(&balancer), size, &|_| true);
+        prop_assert!(batch.len() <= size);
+        let nearest = order_tour(&Preview::new(&balancer), start, &batch, TourOrder::NearestNeighbor);
+        let two_opt = order_tour(&Preview::new(&balancer), start, &batch, TourOrder::TwoOpt);
+        prop_assert_eq!(nearest.len(), two_opt.len());
+        prop_assert!(tour_length(&balancer, start, &two_opt) <= tour_length(&balancer, start, &nearest));
+        for tour in [nearest, two_opt] {
+            let mut state = balancer.clone();
+            for ins in tour {
+                prop_assert!(batch.contains(&ins));
+                let before = state.group_balance();
+                state.reassign_group(ins.transfer_point, ins.to_group);
+                prop_assert!(state.group_balance() < before);
+            }
+        }
+    }
+}
+
+#[test]
+#[should_panic(expected = "only tiles of a group can be reassigned")]
+fn reassigning_a_wall_panics() {
+    let mut balancer = balancer(2, 2, 1, vec![Cell::Wall, Cell::Group(0), Cell::Group(1), Cell::Empty], vec![0, 5, 5, 0]);
+    balancer.reassign_group(0, 1);
+}
+
+#[test]
+fn target_distance_takes_a_single_value_per_tile() {
+    let targets = TargetDistance { targets: vec![10.0, 10.0] };
+    assert!(targets.check(2, 1).is_ok());
+    assert!(targets.check(3, 1).is_err());
+    assert!(targets.check(2, 2).is_err());
+}
+
+#[test]
+fn a_map_without_groups_has_no_transfers() {
+    let balancer = balancer(2, 0, 1, vec![Cell::Wall, Cell::Empty, Cell::Empty, Cell::Wall], vec![0; 4]);
+    assert!(balancer.next_instruction().is_none());
+}
+
+#[test]
+fn targets_are_compared_to_the_plain_sums() {
+    let cells = vec![Cell::Group(0), Cell::Group(0), Cell::Group(1), Cell::Group(1)];
+    let mut balancer = balancer(2, 2, 1, cells, vec![5, 5, 3, 3]);
+    balancer.set_capacities(vec![1.0, 0.5]);
+    balancer.set_measure(Box::new(TargetDistance { targets: vec![10.0, 6.0] }));
+    assert_eq!(balancer.group_balance(), 0.0);
+}
+
+#[test]
+#[should_panic(expected = "weights must be non-negative and finite")]
+fn negative_weights_panic() {
+    let mut balancer = balancer(2, 2, 1, vec![Cell::Group(0), Cell::Group(1), Cell::Empty, Cell::Empty], vec![5, 3, 0, 0]);
+    balancer.set_weights(vec![-1.0]);
+}
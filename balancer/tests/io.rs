@@ -0,0 +1,109 @@
+//! `read_csv` and `read_grid`: what they read, and the maps they refuse. `write_csv`: what it writes.
+
+use balancer::{
+    io::{read_csv, read_grid, write_csv, write_csv_summary, GridMap, MapFileError, MAX_GROUPS},
+    Balancer, Cell, FourConnected, StopCriteria, Threshold
+};
+
+/// Groups and values row by row, for comparing with the map as written.
+fn rows(map: &GridMap) -> Vec<Vec<(Cell, u32)>> {
+    (0..map.grid.height).map(|y| (0..map.grid.width).map(|x| {
+        let point = map.grid.index((x, y));
+        (map.tile_groups[point], map.tile_values[point * map.attribute_count])
+    }).collect()).collect()
+}
+
+#[test]
+fn a_grid_map() {
+    let map = read_grid("// a comment\n0:5 # .\n\n1:7 0:2 .\n".as_bytes()).unwrap();
+    assert_eq!((map.grid.width, map.grid.height), (3, 2));
+    assert_eq!(rows(&map), vec![
+        vec![(Cell::Group(0), 5), (Cell::Wall, 0), (Cell::Empty, 0)],
+        vec![(Cell::Group(1), 7), (Cell::Group(0), 2), (Cell::Empty, 0)]
+    ]);
+    assert_eq!((map.attribute_count, map.group_count), (1, 2));
+}
+
+#[test]
+fn a_grid_map_with_several_values() {
+    let map = read_grid("0:1,2 1:3,4\n".as_bytes()).unwrap();
+    assert_eq!(map.attribute_count, 2);
+    assert_eq!(map.tile_values, vec![1, 2, 3, 4]);
+}
+
+#[test]
+fn bad_grid_maps_are_errors() {
+    assert!(matches!(read_grid("0:1 x\n".as_bytes()), Err(MapFileError::BadCell { line: 1, .. })));
+    assert!(matches!(read_grid("0:1 .\n0:1\n".as_bytes()),
+        Err(MapFileError::Ragged { line: 2, expected: 2, found: 1 })));
+    assert!(matches!(read_grid("0:1 0:1,2\n".as_bytes()),
+        Err(MapFileError::Attributes { line: 1, expected: 1, found: 2 })));
+    assert!(matches!(read_grid("// nothing\n".as_bytes()), Err(MapFileError::Empty)));
+    let huge = format!("{}:1\n", MAX_GROUPS);
+    assert!(matches!(read_grid(huge.as_bytes()), Err(MapFileError::TooManyGroups { line: 1, .. })));
+}
+
+#[test]
+fn a_csv_map() {
+    let map = read_csv("x,y,value,group\n0,0,5,0\n2,1,7,1\n1,0,0,#\n".as_bytes()).unwrap();
+    assert_eq!((map.grid.width, map.grid.height), (3, 2));
+    assert_eq!(rows(&map), vec![
+        vec![(Cell::Group(0), 5), (Cell::Wall, 0), (Cell::Empty, 0)],
+        vec![(Cell::Empty, 0), (Cell::Empty, 0), (Cell::Group(1), 7)]
+    ]);
+    assert_eq!((map.attribute_count, map.group_count), (1, 2));
+}
+
+#[test]
+fn a_csv_map_with_several_values() {
+    let map = read_csv("0,0,1,2,0\n1,0,3,4,1\n".as_bytes()).unwrap();
+    assert_eq!(map.attribute_count, 2);
+    assert_eq!(map.tile_values, vec![1, 2, 3, 4]);
+}
+
+#[test]
+fn bad_csv_maps_are_errors() {
+    assert!(matches!(read_csv("0,0,1\n".as_bytes()), Err(MapFileError::BadField { line: 1, field: "row", .. })));
+    assert!(matches!(read_csv("0,-1,1,0\n".as_bytes()), Err(MapFileError::BadField { line: 1, field: "y", .. })));
+    assert!(matches!(read_csv("0,0,1,0\n0,0,2,1\n".as_bytes()),
+        Err(MapFileError::DuplicateTile { line: 2, x: 0, y: 0 })));
+    assert!(matches!(read_csv("0,0,1,0\n1,0,1,2,1\n".as_bytes()),
+        Err(MapFileError::Attributes { line: 2, expected: 1, found: 2 })));
+    assert!(matches!(read_csv("x,y,value,group\n".as_bytes()), Err(MapFileError::Empty)));
+}
+
+#[test]
+fn csv_maps_cannot_be_huge() {
+    assert!(matches!(read_csv("99999999,99999999,3,1\n".as_bytes()), Err(MapFileError::TooLarge { .. })));
+    let at_the_end = format!("{},0,3,1\n", usize::MAX);
+    assert!(matches!(read_csv(at_the_end.as_bytes()), Err(MapFileError::TooLarge { .. })));
+    assert!(matches!(read_csv("0,0,1,0\n1,0,1,3000000000\n".as_bytes()),
+        Err(MapFileError::TooManyGroups { line: 2, group: 3000000000 })));
+}
+
+#[test]
+fn an_exported_csv_reads_back_as_the_balanced_map() {
+    for text in ["0:9 0:8 0:7 1:1\n. # 1:2 1:3\n", "0:9,1 0:8,2 0:7,3 1:1,4\n. # 1:2,5 1:3,6\n"] {
+        let map = read_grid(text.as_bytes()).unwrap();
+        let mut balancer = Balancer::with_attributes(map.tile_groups, map.tile_values, map.attribute_count
+            , map.group_count, FourConnected { grid: map.grid });
+        balancer.run(&StopCriteria { threshold: Some(Threshold::Absolute(0.0)), ..StopCriteria::default() });
+        assert!((0..balancer.len()).any(|p| balancer.group(p) != balancer.initial_group(p)));
+        let mut csv = Vec::new();
+        write_csv(&mut csv, &map.grid, &balancer).unwrap();
+        let exported = read_csv(csv.as_slice()).unwrap();
+        assert_eq!((exported.grid.width, exported.grid.height), (map.grid.width, map.grid.height));
+        assert_eq!(exported.tile_groups, balancer.tile_groups());
+        let values: Vec<u32> = (0..balancer.len()).flat_map(|p| balancer.values(p).to_vec()).collect();
+        assert_eq!(exported.tile_values, values);
+    }
+}
+
+#[test]
+fn the_csv_summary_is_csv() {
+    let map = read_grid("0:9 1:1\n".as_bytes()).unwrap();
+    let balancer = Balancer::new(map.tile_groups, map.tile_values, map.group_count, FourConnected { grid: map.grid });
+    let mut summary = Vec::new();
+    write_csv_summary(&mut summary, &balancer).unwrap();
+    assert_eq!(String::from_utf8(summary).unwrap(), "name,value\ngroup_balance,8\ngroup_0_sum,9\ngroup_1_sum,1\n");
+}
@@ -15,6 +15,12 @@ mod ascii;
 use ascii::AsciiPlugin;
 mod map;
 use map::MapPlugin;
+mod settings;
+mod transfer_log;
+use transfer_log::TransferLogPlugin;
+mod solver_control;
+mod pathfinding;
+use solver_control::SolverControlPlugin;
 
 
 /// This example illustrates how to create UI text and update it in a system. It displays the
@@ -37,6 +43,8 @@ fn main() {
         .add_plugin(DebugPlugin)
         .add_plugin(EasingsPlugin)
         .add_plugin(MapPlugin)
+        .add_plugin(TransferLogPlugin)
+        .add_plugin(SolverControlPlugin)
         .run();
 }
 
@@ -1,21 +1,304 @@
-use std::{collections::HashMap, ops::RangeInclusive};
-use itertools::{Itertools, iproduct};
 use bevy::{prelude::*};
 use bevy_inspector_egui::Inspectable;
-use rand::{Rng};
-use crate::player::{State, Player};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use std::path::Path;
+use balancer::{
+    io::{read_map_file, write_export_file, GridMap}, order_tour, plan_batch, tour_length, BalanceMeasure, Balancer, Cell,
+    CoefficientOfVariation, Grid, MaxMinSpread, MeanPairwiseDifference, Preview, RadiusNeighborhood, RunTracker, SolverStatus,
+    StopCriteria, TourOrder, Variance
+};
+use std::{collections::{HashMap, HashSet}, time::Duration};
+use bevy_easings::{Ease, EaseFunction, EasingType};
+use crate::player::{nearest, recolor, Player, TileSprites};
+use crate::pathfinding::{parse_terrains, Terrain, Walkability};
 use crate::ascii::{AsciiSheet, spawn_ascii_sprite};
+use crate::settings::{parsed_setting, setting};
+use crate::transfer_log::Replay;
+use crate::solver_control::SolverControl;
 
 pub const TILE_SIZE:f32 = 32.0;
-pub const WORLD_X:[i32;2] = [-50,50]; // element at 0 must be smaller than element at 1
+pub const WORLD_X:[i32;2] = [-50,50]; // extent of the generated map; element at 0 must be smaller than element at 1
 pub const WORLD_Y:[i32;2] = [-50,50]; 
-pub const GROUP_SIZE:u8 = 3; // must be >= 3
-pub const COLORS:[Color;GROUP_SIZE as usize] = [Color::SILVER, Color::GOLD, Color::BLUE];
-const THRESHOLD:f32 = 1500.0;
+const COLORS:[Color;3] = [Color::SILVER, Color::GOLD, Color::BLUE];
+const RADIUS:usize = 2;
 
+/// Inspector-facing mirror of `balancer::ExecutionPlan`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Inspectable)]
+pub enum ExecutionPlan {
+    /// Take the first candidate that improves the balance.
+    #[default]
+    Eager,
+    /// Take the candidate that improves the balance the most.
+    Greedy,
+    /// Take the improving candidate closest to the anchor.
+    Distance(DistanceAnchor)
+}
+
+/// Inspector-facing mirror of `balancer::DistanceAnchor`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Inspectable)]
+pub enum DistanceAnchor {
+    /// The boundary tile of the taking group that found the candidate (p1 in the readme).
+    #[default]
+    TransferPoint,
+    /// The centroid of all tiles in the taking group.
+    Centroid
+}
+
+impl From<ExecutionPlan> for balancer::ExecutionPlan {
+    fn from(plan: ExecutionPlan) -> Self {
+        match plan {
+            ExecutionPlan::Eager => balancer::ExecutionPlan::Eager,
+            ExecutionPlan::Greedy => balancer::ExecutionPlan::Greedy,
+            ExecutionPlan::Distance(DistanceAnchor::TransferPoint) => 
+                balancer::ExecutionPlan::Distance(balancer::DistanceAnchor::TransferPoint),
+            ExecutionPlan::Distance(DistanceAnchor::Centroid) => 
+                balancer::ExecutionPlan::Distance(balancer::DistanceAnchor::Centroid)
+        }
+    }
+}
+
+/// The balance measures that can be picked in the inspector. See `balancer::BalanceMeasure`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Inspectable)]
+pub enum Measure {
+    /// Mean pairwise absolute difference, from the readme.
+    #[default]
+    MeanPairwiseDifference,
+    Variance,
+    MaxMinSpread,
+    CoefficientOfVariation
+}
+
+impl Measure {
+    fn to_balance_measure(self) -> Box<dyn BalanceMeasure> {
+        match self {
+            Measure::MeanPairwiseDifference => Box::new(MeanPairwiseDifference),
+            Measure::Variance => Box::new(Variance),
+            Measure::MaxMinSpread => Box::new(MaxMinSpread),
+            Measure::CoefficientOfVariation => Box::new(CoefficientOfVariation)
+        }
+    }
+}
+
+/// How the solver hands transfers to a worker: one at a time, or in batches of improving
+/// transfers ordered into a short walk. Set with `--schedule single|nearest|two-opt`
+/// (or `BALANCE_SCHEDULE`), and the batch size with `--batch-size N`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Inspectable)]
+pub enum Schedule {
+    /// The next transfer, wherever it is.
+    #[default]
+    Single,
+    /// A batch, always walking to the nearest transfer next.
+    NearestNeighbor,
+    /// A batch, in the nearest neighbor order shortened by 2-opt moves.
+    TwoOpt
+}
+
+impl Schedule {
+    fn tour_order(self) -> Option<TourOrder> {
+        match self {
+            Schedule::Single => None,
+            Schedule::NearestNeighbor => Some(TourOrder::NearestNeighbor),
+            Schedule::TwoOpt => Some(TourOrder::TwoOpt)
+        }
+    }
+}
+
+impl std::str::FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "single" => Ok(Schedule::Single),
+            _ => match s.parse::<TourOrder>()? {
+                TourOrder::NearestNeighbor => Ok(Schedule::NearestNeighbor),
+                TourOrder::TwoOpt => Ok(Schedule::TwoOpt)
+            }
+        }
+    }
+}
+
+/// Inspector-facing mirror of `balancer::Threshold`, or the default of the balance measure.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Inspectable)]
+pub enum Threshold {
+    /// `BalanceMeasure::default_threshold` of the measure in use.
+    #[default]
+    MeasureDefault,
+    /// In the units of the balance measure.
+    Absolute(f32),
+    /// A fraction of the total value of all groups.
+    Total(f32),
+    /// A fraction of the mean group sum.
+    Mean(f32)
+}
+
+impl From<Threshold> for Option<balancer::Threshold> {
+    fn from(threshold: Threshold) -> Self {
+        match threshold {
+            Threshold::MeasureDefault => None,
+            Threshold::Absolute(t) => Some(balancer::Threshold::Absolute(t)),
+            Threshold::Total(f) => Some(balancer::Threshold::Total(f)),
+            Threshold::Mean(f) => Some(balancer::Threshold::Mean(f))
+        }
+    }
+}
+
+impl From<Option<balancer::Threshold>> for Threshold {
+    fn from(threshold: Option<balancer::Threshold>) -> Self {
+        match threshold {
+            None => Threshold::MeasureDefault,
+            Some(balancer::Threshold::Absolute(t)) => Threshold::Absolute(t),
+            Some(balancer::Threshold::Total(f)) => Threshold::Total(f),
+            Some(balancer::Threshold::Mean(f)) => Threshold::Mean(f)
+        }
+    }
+}
+
+/// Inspector-facing mirror of `balancer::StopCriteria`, where 0 turns a limit off.
+/// Each can be set on the command line: `--threshold T|total:F|mean:F`, `--max-transfers N`,
+/// `--time-limit SECS`, `--no-improvement N` and `--min-improvement F`.
+#[derive(Clone, PartialEq, Debug, Inspectable)]
+pub struct StopSettings {
+    pub threshold: Threshold,
+    pub max_transfers: usize,
+    /// Seconds since the solver (re)started, walking included.
+    pub time_limit: f32,
+    /// Stop when the last this many transfers lowered the balance by less than `min_improvement` of it.
+    pub no_improvement: usize,
+    pub min_improvement: f32
+}
+
+impl Default for StopSettings {
+    fn default() -> Self {
+        let defaults = StopCriteria::default();
+        StopSettings {
+            threshold: setting("threshold").and_then(|t| t.parse::<balancer::Threshold>().ok())
+                .or(defaults.threshold).into(),
+            max_transfers: parsed_setting("max-transfers", 0),
+            time_limit: parsed_setting("time-limit", 0.0),
+            no_improvement: parsed_setting("no-improvement", 0),
+            min_improvement: parsed_setting("min-improvement", defaults.min_improvement)
+        }
+    }
+}
+
+impl From<&StopSettings> for StopCriteria {
+    fn from(settings: &StopSettings) -> Self {
+        StopCriteria {
+            threshold: settings.threshold.into(),
+            max_transfers: Some(settings.max_transfers).filter(|&n| n > 0),
+            time_limit: Some(settings.time_limit).filter(|&t| t > 0.0).map(Duration::from_secs_f32),
+            no_improvement: Some(settings.no_improvement).filter(|&n| n > 0),
+            min_improvement: settings.min_improvement
+        }
+    }
+}
+
+/// Seed of every random choice in the demo: the map, and the player's routes.
+/// Set with `--seed N` or `BALANCE_SEED`, otherwise picked at random and printed,
+/// so that a run can be replayed.
+pub struct MapSeed(pub u64);
+
+impl Default for MapSeed {
+    fn default() -> Self {
+        let seed = parsed_setting("seed", rand::thread_rng().gen());
+        println!("Map seed: {} (run with --seed {} to get the same map again)", seed, seed);
+        MapSeed(seed)
+    }
+}
+
+impl MapSeed {
+    /// An RNG for one consumer of randomness. Each `stream` gets its own sequence, so
+    /// the map stays the same whatever else draws random numbers.
+    pub fn rng(&self, stream: u64) -> StdRng {
+        StdRng::seed_from_u64(self.0 ^ stream.wrapping_mul(0x9E37_79B9_7F4A_7C15))
+    }
+}
+
+/// `MapSeed::rng` stream of the map generator.
+const MAP_STREAM: u64 = 0;
+
+/// Number of groups on the map. Set with `--groups N` or `BALANCE_GROUPS`, at least 2.
+pub struct GroupCount(pub usize);
+
+impl Default for GroupCount {
+    fn default() -> Self {
+        GroupCount(parsed_setting("groups", 3).max(2))
+    }
+}
+
+/// Number of values per tile on the generated map. Set with `--attributes N` or `BALANCE_ATTRIBUTES`.
+/// The first one is the value shown on the tile, the others are random in `1..=10`.
+pub struct AttributeCount(pub usize);
+
+impl Default for AttributeCount {
+    fn default() -> Self {
+        AttributeCount(parsed_setting("attributes", 1).max(1))
+    }
+}
+
+/// Where the map sits in the world. Tile `(x, y)` is drawn at `(x + WORLD_X[0], y + WORLD_Y[0]) * TILE_SIZE`,
+/// so the generated map spans `WORLD_X` by `WORLD_Y`, and a loaded map starts at the same corner.
+pub struct MapLayout {
+    pub grid: Grid
+}
+
+impl MapLayout {
+    pub fn world_position(&self, (x, y): (usize, usize)) -> Vec2 {
+        Vec2::new((x as i32 + WORLD_X[0]) as f32, (y as i32 + WORLD_Y[0]) as f32) * TILE_SIZE
+    }
+
+    /// The tile at a world position, `None` outside of the map.
+    pub fn grid_position(&self, position: Vec2) -> Option<(usize, usize)> {
+        let x = (position.x / TILE_SIZE).round() as i32 - WORLD_X[0];
+        let y = (position.y / TILE_SIZE).round() as i32 - WORLD_Y[0];
+        let (x, y) = (usize::try_from(x).ok()?, usize::try_from(y).ok()?);
+        (x < self.grid.width && y < self.grid.height).then_some((x, y))
+    }
+}
+
+/// The map to show, until `spawn_map` turns it into tiles and `MapData`.
+struct LoadedMap(Option<GridMap>);
+
+/// One color per group. The first three are the classic demo colors,
+/// the rest are spread around the hue wheel.
+pub struct Palette(pub Vec<Color>);
+
+impl Palette {
+    pub fn new(group_count: usize) -> Palette {
+        Palette((0..group_count).map(|g| {
+            match COLORS.get(g) {
+                Some(c) => *c,
+                // golden angle steps keep consecutive groups far apart
+                None => Color::hsl((g as f32 * 137.508) % 360.0, 0.7, 0.55)
+            }
+        }).collect())
+    }
+
+    pub fn color(&self, group: usize) -> Color {
+        self.0[group]
+    }
+}
+
+/// Sent every time a tile changes group. `value` is the tile's first value, the one shown on it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferCompleted {
+    pub point: (usize, usize),
+    pub from: usize,
+    pub to: usize,
+    pub value: u32,
+    pub balance_before: f32,
+    pub balance_after: f32
+}
+
+#[derive(Clone, Copy, Debug)]
 pub struct Instruction {
     pub transfer_point: (usize, usize),
-    pub to_group: u8
+    /// The group the tile is in when the instruction is handed out.
+    pub from_group: usize,
+    pub to_group: usize,
+    /// Whether the transfer must still lower the balance when it is carried out.
+    /// Replayed transfers are carried out as they were recorded.
+    pub improving: bool
 }
 
 #[derive(Component)]
@@ -23,128 +306,127 @@ pub struct Tile {
     pub loc: (usize, usize)
 }
 
+/// The map component. The algorithm itself lives in `balancer`, the other fields
+/// are what the inspector shows and edits.
 #[derive(Component, Inspectable)]
 pub struct MapData {
     #[inspectable(ignore)]
-    pub tile_groups : Vec<Vec<u8>>,
+    pub balancer: Balancer,
     #[inspectable(ignore)]
-    tile_values : Vec<Vec<u32>>,
-    group_sum: [u32; GROUP_SIZE as usize],
+    pub grid: Grid,
+    /// `group_sum[k][g]` is the sum of attribute `k` over group `g`.
+    group_sum: Vec<Vec<u32>>,
     pub group_balance: f32,
+    /// One weight per attribute.
+    pub weights: Vec<f32>,
+    /// One capacity per group. Groups aim for sums in proportion to it.
+    pub capacities: Vec<f32>,
+    pub plan: ExecutionPlan,
+    pub measure: Measure,
+    /// Only take transfers that keep the giving group connected.
+    pub contiguous: bool,
+    pub stop: StopSettings,
+    pub schedule: Schedule,
+    /// Most transfers in one batch, when the schedule batches them.
+    pub batch_size: usize,
+    /// Transfers carried out so far, less the ones undone. Dropped transfers do not count.
+    pub transfers: usize,
+    /// For tiles some worker could not find a path to, the ids of those workers. They are not
+    /// sent there again, and the solver leaves a tile alone once no worker can reach it.
     #[inspectable(ignore)]
-    pub tile_neighbors: HashMap<(usize, usize), Vec<(usize, usize)>>,
+    pub unreachable: HashMap<usize, HashSet<usize>>,
+    /// How the solver ended, `None` while it is still going.
+    #[inspectable(ignore)]
+    pub status: Option<SolverStatus>,
+    #[inspectable(ignore)]
+    tracker: RunTracker,
+    #[inspectable(ignore)]
+    applied_stop: StopSettings,
+    #[inspectable(ignore)]
+    applied_measure: Measure,
     #[inspectable(ignore)]
     interval: f32
 }
 impl MapData {
 
-    fn update_balance(&mut self) {
-        self.group_balance = MapData::calc_balance(&self.group_sum);
+    fn new(balancer: Balancer, grid: Grid) -> MapData {
+        let mut map = MapData {group_sum: Vec::new(), group_balance: 0.0, weights: balancer.weights().to_vec()
+            , capacities: balancer.capacities().to_vec()
+            , plan: ExecutionPlan::default(), measure: Measure::default(), contiguous: false
+            , stop: StopSettings::default(), schedule: parsed_setting("schedule", Schedule::default())
+            , batch_size: parsed_setting("batch-size", 8).max(1), transfers: 0, unreachable: HashMap::new(), status: None
+            , tracker: RunTracker::new(StopCriteria::default(), &balancer), applied_stop: StopSettings::default()
+            , applied_measure: Measure::default()
+            , balancer, grid, interval: 0.0};
+        map.sync();
+        map.restart();
+        map
     }
 
-    fn calc_balance(data:&[u32; GROUP_SIZE as usize]) -> f32 {
-        let mut s:i32 = 0;
-        let mut counter: f32 = 0.0;
-        for (i, j) in (0..data.len()).tuple_combinations::<(usize, usize)>() {
-            s += (data[i] as i32 - data[j] as i32).abs();
-            counter += 1.0;
-        }
-        s as f32 / counter
+    /// Copies what the inspector shows from the balancer.
+    fn sync(&mut self) {
+        self.group_sum = (0..self.balancer.attribute_count())
+            .map(|k| self.balancer.attribute_sums(k).to_vec())
+            .collect();
+        self.group_balance = self.balancer.group_balance();
     }
 
-    fn calculate_neighbors(&mut self, radius: usize) {
-        let x_range = (WORLD_X[1]-WORLD_X[0]) as usize;
-        let y_range = (WORLD_Y[1]-WORLD_Y[0]) as usize;
-        for (x,y) in iproduct!(1..x_range, 1..y_range){
-            let group:u8 = self.tile_groups[x][y];
-            if group == GROUP_SIZE {
-                continue
-            }
-            for (xx,yy) in MapData::get_nbhd((x,y), radius) {
-                if  self.tile_groups[xx][yy] < GROUP_SIZE && self.tile_groups[xx][yy] != group {
-                    let vec = self.tile_neighbors.get_mut(&(x,y)).unwrap();
-                    vec.push((xx,yy));
-                }
-            } // same code used twice.
+    /// Hands the settings picked in the inspector to the balancer. A finished solver
+    /// starts again when they change.
+    fn apply_settings(&mut self) {
+        let plan: balancer::ExecutionPlan = self.plan.into();
+        let mut changed = plan != self.balancer.plan() || self.contiguous != self.balancer.preserve_contiguity();
+        self.balancer.set_plan(plan);
+        self.balancer.set_preserve_contiguity(self.contiguous);
+        if self.measure != self.applied_measure {
+            self.balancer.set_measure(self.measure.to_balance_measure());
+            self.applied_measure = self.measure;
+            changed = true;
         }
-    }
-
-    pub fn reassign_group(&mut self, point:(usize, usize), to_group:u8, radius: usize) {
-        let v = self.tile_values[point.0][point.1];
-        let from_group = self.tile_groups[point.0][point.1];
-        self.group_sum[from_group as usize] -= v;
-        self.group_sum[to_group as usize] += v;
-        self.tile_groups[point.0][point.1] = to_group;
-        self.update_balance();
-        let vec = self.tile_neighbors.get_mut(&point).unwrap();
-        vec.clear();
-        for (xx, yy) in MapData::get_nbhd(point, radius) {
-            if  self.tile_groups[xx][yy] < GROUP_SIZE && self.tile_groups[xx][yy] != to_group {
-                vec.push((xx,yy));
-            }
+        if self.weights.len() == self.balancer.attribute_count()
+            && self.weights.iter().all(|&w| w >= 0.0 && w.is_finite()) && self.weights != self.balancer.weights()
+        {
+            self.balancer.set_weights(self.weights.clone());
+            changed = true;
         }
-    }
-
-    fn find_min_group(&self) -> usize {
-        let mut min_idx: usize = 0;
-        let mut min_val = u32::MAX;
-        for i in 0..self.group_sum.len() {
-            if self.group_sum[i] < min_val {
-                min_val = self.group_sum[i];
-                min_idx = i;
-            }
+        if self.capacities.len() == self.balancer.group_count() && self.capacities.iter().all(|&c| c > 0.0 && c.is_finite())
+            && self.capacities != self.balancer.capacities()
+        {
+            self.balancer.set_capacities(self.capacities.clone());
+            changed = true;
         }
-        min_idx
-    }
-
-    fn create_player_instruction(&self, group:usize) -> Option<Instruction>{
-        for (key, nbhd) in self.tile_neighbors.iter(){
-            if !nbhd.is_empty() && self.tile_groups[key.0][key.1] == group as u8 {
-                // After adding self.tile_groups[key.0][key.1] == group as u8
-                // nothing worked anymore... ...
-                // println!("Here");
-                for candidate in nbhd.iter() {
-                    let x = candidate.0;
-                    let y = candidate.1;
-                    let giver:u8 = self.tile_groups[x][y];
-                    let taker:u8 = self.tile_groups[key.0][key.1];
-                    let test_point = (x,y);
-                    if self.should_take(test_point, giver, taker) {
-                        return Some(Instruction {transfer_point: test_point, to_group: taker})
-                    }
-                }
-            } 
+        if self.stop != self.applied_stop {
+            self.applied_stop = self.stop.clone();
+            changed = true;
+        }
+        if changed {
+            self.sync();
+            self.restart();
         }
-        None
     }
 
-    fn should_take(&self, giver_point:(usize, usize), giver_group:u8, taker_group:u8,) -> bool {
-        let mut temp_group_sum:[u32; GROUP_SIZE as usize] = self.group_sum.clone();
-        let value_transfered:u32 = self.tile_values[giver_point.0][giver_point.1];
-
-        temp_group_sum[giver_group as usize] -= value_transfered;
-        temp_group_sum[taker_group as usize] += value_transfered;
-
-        let new_balance = MapData::calc_balance(&temp_group_sum);
-
-        new_balance < self.group_balance
-
+    /// Starts the solver again, with a fresh clock for the stopping criteria.
+    pub fn restart(&mut self) {
+        self.status = None;
+        self.tracker = RunTracker::new((&self.stop).into(), &self.balancer);
     }
 
-    fn get_nbhd(point:(usize, usize), radius:usize) 
-        -> itertools::Product<RangeInclusive<usize>,RangeInclusive<usize>> 
-    {
-        let x_range = (WORLD_X[1]-WORLD_X[0]) as usize;
-        let y_range = (WORLD_Y[1]-WORLD_Y[0]) as usize;
-        let x = point.0;
-        let y = point.1;
-        let x_min = 0.max(x as i32 - radius as i32) as usize;
-        let x_max = (x_range as i32).min(x as i32 + radius as i32) as usize;
-        let y_min = 0.max(y as i32 - radius as i32) as usize;
-        let y_max = (y_range as i32).min(y as i32 + radius as i32) as usize;
-
-        iproduct!(x_min..=x_max, y_min..=y_max)
+    /// Whether `instr` still applies to the map as it is now: the tile is still in the group it was
+    /// planned from and, for a planned transfer, moving it still lowers the balance.
+    pub fn can_carry_out(&self, instr: &Instruction) -> bool {
+        let index = self.grid.index(instr.transfer_point);
+        self.balancer.group(index) == Some(instr.from_group)
+            && (!instr.improving || self.balancer.should_take(index, instr.from_group, instr.to_group))
+    }
 
+    pub fn reassign_group(&mut self, point:(usize, usize), to_group:usize) -> TransferCompleted {
+        let index = self.grid.index(point);
+        let from = self.balancer.group(index).expect("only group tiles are transferred");
+        let balance_before = self.balancer.group_balance();
+        self.balancer.reassign_group(index, to_group);
+        self.sync();
+        TransferCompleted {point, from, to: to_group, value: self.balancer.value(index)
+            , balance_before, balance_after: self.balancer.group_balance()}
     }
 }
 
@@ -152,89 +434,126 @@ pub struct MapPlugin;
 
 impl Plugin for MapPlugin {
     fn build(&self, app:&mut App){
-        app.add_startup_system(generate_map)
-        .add_system(start_algorithm.label("algorithm"));
+        let seed = MapSeed::default();
+        let (map, groups) = match setting("map") {
+            Some(path) => load_map(Path::new(&path)).unwrap_or_else(|e| {
+                eprintln!("Cannot load map {}: {}", path, e);
+                std::process::exit(1)
+            }),
+            None => {
+                let groups = GroupCount::default();
+                (generate_map(&seed, &groups, &AttributeCount::default()), groups)
+            }
+        };
+        // `--impassable wall,empty` (or `BALANCE_IMPASSABLE`): the tiles workers walk around
+        let impassable = setting("impassable").map_or(Ok(vec![Terrain::Wall]), |s| parse_terrains(&s))
+            .unwrap_or_else(|e| {
+                eprintln!("Cannot use impassable terrain: {}", e);
+                std::process::exit(1)
+            });
+        app.insert_resource(seed)
+        .insert_resource(Palette::new(groups.0))
+        .insert_resource(groups)
+        .insert_resource(AttributeCount(map.attribute_count))
+        .insert_resource(MapLayout {grid: map.grid})
+        .insert_resource(Walkability::new(map.grid, &map.tile_groups, &impassable))
+        .insert_resource(LoadedMap(Some(map)))
+        .add_event::<TransferCompleted>()
+        .add_startup_system(spawn_map)
+        .add_system(start_algorithm.label("algorithm"))
+        .add_system(claim_animation.after("movement"))
+        .add_system(export_map);
     }
 }
 
-fn generate_map(mut commands: Commands, ascii:Res<AsciiSheet>, asset_server: Res<AssetServer>){
-    let mut tiles: Vec<Entity> = Vec::new();
-    let mut tile_groups: Vec<Vec<u8>> = Vec::new();
-    let mut tile_values: Vec<Vec<u32>> = Vec::new();
-    let mut tile_neighbors: HashMap<(usize, usize), Vec<(usize, usize)>> = HashMap::new();
-    let mut group_sums: [u32; GROUP_SIZE as usize] = [0; GROUP_SIZE as usize];
-    // const OFFSET:Vec2 = Vec2::new(30.,-15.);
+/// The initial group of a tile: the top half of the map is group 0, the bottom half
+/// is cut into equal angular sectors for the other groups.
+fn initial_group(x: i32, y: i32, group_count: usize) -> usize {
+    if y > 0 {
+        return 0
+    }
+    // angle from the left (-x) axis, going through -y to the right; in [0, pi]
+    let angle = (y as f32).abs().atan2(-(x as f32)).clamp(0.0, std::f32::consts::PI);
+    let sector = (angle / std::f32::consts::PI * (group_count - 1) as f32) as usize;
+    1 + sector.min(group_count - 2)
+}
+
+/// Loads the map given with `--map FILE` or `BALANCE_MAP`, a `.csv` file or a grid file
+/// (see `balancer::io`). Without `--groups`, the groups are the ones on the map.
+fn load_map(path: &Path) -> Result<(GridMap, GroupCount), Box<dyn std::error::Error>> {
+    let map = read_map_file(path)?;
+    let groups = GroupCount(parsed_setting("groups", map.group_count).max(2));
+    map.check_groups(groups.0)?;
+    Ok((map, groups))
+}
+
+/// A random map spanning `WORLD_X` by `WORLD_Y`, walled in, with a third of the tiles filled.
+fn generate_map(seed: &MapSeed, groups: &GroupCount, attributes: &AttributeCount) -> GridMap {
+    let mut rng = seed.rng(MAP_STREAM);
+    let mut tile_groups: Vec<Cell> = Vec::new();
+    let mut tile_values: Vec<u32> = Vec::new();
     for x in WORLD_X[0]..=WORLD_X[1] {
-        let mut row_tile_groups:Vec<u8>= Vec::new();
-        let mut row_tile_values:Vec<u32>= Vec::new();
         for y in WORLD_Y[0]..=WORLD_Y[1]{
-            let x_idx = (x - WORLD_X[0]) as usize;
-            let y_idx = (y - WORLD_Y[0]) as usize;
-            tile_neighbors.insert((x_idx, y_idx), Vec::new());
             if y == WORLD_Y[0] || y == WORLD_Y[1] || x == WORLD_X[0] || x == WORLD_X[1] {
-                let tile = spawn_ascii_sprite(
-                    &mut commands,
-                    &ascii,
-                    '#' as usize,
-                    Color::rgb(0.9,0.9,0.9),
-                    Vec3::new(x as f32 * TILE_SIZE, y as f32 * TILE_SIZE, 100.0),
-                    (x_idx, y_idx)
-                );
-                tiles.push(tile);
-                row_tile_groups.push(GROUP_SIZE);
-                row_tile_values.push(0);
-            } else {
-                let mut rng = rand::thread_rng();
-                if rng.gen_range(0..100) < 33 {
-                    let mut group: u8 = 0;
-                    if x < 0 && y <= 0 {
-                        group = 1;
-                    } else if x >= 0 && y <= 0 {
-                        group = 2;
-                    }
-                    let value:u32 = rng.gen_range(1..=(group as u32 + 1)*10);
-                    row_tile_groups.push(group);
-                    row_tile_values.push(value);
-                    group_sums[group as usize] += value;
-                    let mut c = COLORS[group as usize];
-                    c.set_a(0.5);
-                    let num_tile = spawn_ascii_sprite(
-                        &mut commands,
-                        &ascii,
-                        43,
-                        c,
-                        Vec3::new(x as f32 * TILE_SIZE, y as f32 * TILE_SIZE, 100.0),
-                        (x_idx, y_idx)
-                    );
-                    
-                    let text_child = commands.spawn_bundle(Text2dBundle {
-                        text: Text::from_section(value.to_string(),
-                        TextStyle {
-                                     font: asset_server.load("fonts/ArchitectsDaughter-Regular.ttf"),
-                                     font_size: 20.0,
-                                     color: Color::WHITE,
-                                }
-                        )
-                        , transform: Transform::from_translation(Vec3::new(-15.0,25.0,0.0))
-                        , ..Default::default()
-                        
-                    }).id();
-                    commands.entity(num_tile).push_children(&[text_child]);
-                    tiles.push(num_tile);
-                } else{
-                    row_tile_groups.push(GROUP_SIZE);
-                    row_tile_values.push(0);
+                tile_groups.push(Cell::Wall);
+                tile_values.extend(std::iter::repeat_n(0, attributes.0));
+            } else if rng.gen_range(0..100) < 33 {
+                let group = initial_group(x, y, groups.0);
+                tile_groups.push(Cell::Group(group));
+                tile_values.push(rng.gen_range(1..=(group as u32 + 1)*10));
+                for _ in 1..attributes.0 {
+                    tile_values.push(rng.gen_range(1..=10));
                 }
+            } else {
+                tile_groups.push(Cell::Empty);
+                tile_values.extend(std::iter::repeat_n(0, attributes.0));
             }
         }
-        tile_groups.push(row_tile_groups);
-        tile_values.push(row_tile_values);
-    }
-    
-    let mut map = MapData {tile_groups: tile_groups, tile_values: tile_values
-        , group_sum: group_sums, tile_neighbors: tile_neighbors, group_balance: 0.0, interval: 0.0};
-    map.calculate_neighbors(2);
-    map.update_balance();
+    }
+    let grid = Grid::new((WORLD_X[1] - WORLD_X[0] + 1) as usize, (WORLD_Y[1] - WORLD_Y[0] + 1) as usize);
+    GridMap {grid, tile_groups, tile_values, attribute_count: attributes.0, group_count: groups.0}
+}
+
+fn spawn_map(mut commands: Commands, ascii:Res<AsciiSheet>, asset_server: Res<AssetServer>
+    , mut loaded: ResMut<LoadedMap>, groups: Res<GroupCount>, palette: Res<Palette>, layout: Res<MapLayout>
+){
+    let map = loaded.0.take().expect("the map is spawned once");
+    let grid = map.grid;
+    let mut tiles: Vec<Entity> = Vec::new();
+    for (point, cell) in map.tile_groups.iter().enumerate() {
+        let loc = grid.point(point);
+        let pos = layout.world_position(loc).extend(100.0);
+        match *cell {
+            Cell::Wall => {
+                tiles.push(spawn_ascii_sprite(&mut commands, &ascii, '#' as usize, Color::rgb(0.9,0.9,0.9), pos, loc));
+            },
+            Cell::Group(group) => {
+                let value = map.tile_values[point * map.attribute_count];
+                let mut c = palette.color(group);
+                c.set_a(0.5);
+                let num_tile = spawn_ascii_sprite(&mut commands, &ascii, 43, c, pos, loc);
+                let text_child = commands.spawn_bundle(Text2dBundle {
+                    text: Text::from_section(value.to_string(),
+                    TextStyle {
+                                 font: asset_server.load("fonts/ArchitectsDaughter-Regular.ttf"),
+                                 font_size: 20.0,
+                                 color: Color::WHITE,
+                            }
+                    )
+                    , transform: Transform::from_translation(Vec3::new(-15.0,25.0,0.0))
+                    , ..Default::default()
+                }).id();
+                commands.entity(num_tile).push_children(&[text_child]);
+                tiles.push(num_tile);
+            },
+            Cell::Empty => {}
+        }
+    }
+
+    let neighborhood = RadiusNeighborhood {grid, radius: RADIUS};
+    let balancer = Balancer::with_attributes(map.tile_groups, map.tile_values, map.attribute_count
+        , groups.0, neighborhood);
+    let map = MapData::new(balancer, grid);
     commands.spawn_bundle(VisibilityBundle::default())
         .insert(Name::new("Map"))
         .insert(Transform::default())
@@ -244,44 +563,153 @@ fn generate_map(mut commands: Commands, ascii:Res<AsciiSheet>, asset_server: Res
 
 }
 
+/// How long a tile pulses when it changes group.
+const CLAIM_MILLIS: u64 = 300;
+
+/// Plays a short pulse on every tile that changes group: it pops up enlarged and settles back.
+fn claim_animation(mut commands: Commands
+    , mut events: EventReader<TransferCompleted>
+    , tile_query: Query<(Entity, &Tile), Without<Player>>
+    , layout: Res<MapLayout>
+){
+    for e in events.iter() {
+        if let Some((entity, _)) = tile_query.iter().find(|(_, tile)| tile.loc == e.point) {
+            let rest = Transform::from_translation(layout.world_position(e.point).extend(100.0));
+            let claimed = Transform {translation: rest.translation + Vec3::Z, scale: Vec3::splat(1.6), ..rest};
+            commands.entity(entity).insert(claimed.ease_to(rest
+                , EaseFunction::BackOut
+                , EasingType::Once {duration: Duration::from_millis(CLAIM_MILLIS)}));
+        }
+    }
+}
+
+/// Pressing E writes the current assignment to `balanced.csv` and `balanced.json`
+/// (or `<name>.csv` and `<name>.json` with `--export <name>` or `BALANCE_EXPORT`),
+/// and the summary of the CSV to `balanced_summary.csv`.
+fn export_map(keyboard: Res<Input<KeyCode>>, map_query: Query<&MapData>) {
+    if !keyboard.just_pressed(KeyCode::E) {
+        return
+    }
+    let map_data = map_query.single();
+    let name = setting("export").unwrap_or_else(|| "balanced".to_string());
+    for ext in ["csv", "json"] {
+        let path = format!("{}.{}", name, ext);
+        match write_export_file(Path::new(&path), &map_data.grid, &map_data.balancer) {
+            Ok(()) => println!("Exported to {}", path),
+            Err(e) => println!("Cannot export to {}: {}", path, e)
+        }
+    }
+}
+
+/// Hands transfers to the idle workers, each to the idle worker nearest to its tile.
+/// Transfers are planned on the map as it will be once the transfers in flight are carried out,
+/// and never touch a tile in flight or one of its neighbors, so that they do not get in each
+/// other's way. A worker still drops its transfer if it no longer lowers the balance on arrival.
+#[allow(clippy::too_many_arguments)]
 fn start_algorithm(mut query: Query<&mut Player>
     , mut map_query: Query<&mut MapData>
-    , mut tile_query: Query<(&Tile, &mut TextureAtlasSprite), (With<Tile>, Without<Player>)>
+    , mut tile_query: TileSprites
+    , layout: Res<MapLayout>
+    , replay: Option<Res<Replay>>
+    , control: Res<SolverControl>
     , t: Res<Time>
 ){
+    if replay.is_some() || !control.running() {
+        // the transfers come from the log instead, or the solver is paused
+        return
+    }
     let mut map_data = map_query.single_mut();
-    let mut player= query.single_mut();
-    if map_data.group_balance < THRESHOLD {
-        println!("Optimization Completed.");
-        return ()
+    let map_data = &mut *map_data;
+    map_data.apply_settings();
+    if map_data.status.is_some() {
+        return
     }
     map_data.interval += t.delta_seconds();
-    if map_data.interval > 0.05 {
-        map_data.interval -= 0.05;
-        match player.state {
-            State::Idle => {
-                let start_group = map_data.find_min_group();
-                let ins = map_data.create_player_instruction(start_group);
-                match ins {
-                    Some(i) => {
-                        let grid_pos = i.transfer_point;
-                        for (tile, mut tile_sprite) in tile_query.iter_mut() {
-                            if tile.loc == grid_pos {
-                                tile_sprite.color = Color::RED;
-                                break;
-                            }
-                        }
-                        println!("Instruction sent to player. Moving to point {:?}", i.transfer_point);
-                        player.queue_instruction(i);
-                    },
-                    _ => {println!("Something wrong happened.");}
-                }
-    
-            },
-            _ => {}
-    
-        }
+    if map_data.interval <= 0.05 {
+        return
+    }
+    map_data.interval -= 0.05;
+    if !query.iter().any(|p| p.is_idle()) {
+        return
     }
 
+    let mut projected = Preview::new(&map_data.balancer);
+    let mut locked = HashSet::new();
+    let lock = |locked: &mut HashSet<usize>, index: usize| {
+        locked.insert(index);
+        locked.extend(map_data.balancer.neighborhood().neighbors(index));
+    };
+    let mut in_flight = 0;
+    for player in query.iter() {
+        for instr in player.instructions() {
+            let index = map_data.grid.index(instr.transfer_point);
+            if projected.group(index) == Some(instr.from_group) {
+                projected.reassign_group(index, instr.to_group);
+            }
+            lock(&mut locked, index);
+            in_flight += 1;
+        }
+    }
 
-}
\ No newline at end of file
+    let walked: usize = query.iter().map(|p| p.distance).sum();
+    // the stopping criteria count the transfers in flight as if they were done
+    let planned = |in_flight: usize| map_data.transfers + in_flight;
+    let workers = query.iter().count();
+    let mut idle: Vec<_> = query.iter_mut().filter(|p| p.is_idle()).collect();
+    while !idle.is_empty() {
+        let status = map_data.tracker.check(projected.group_balance(), planned(in_flight));
+        let allowed = |point: usize| !locked.contains(&point)
+            && map_data.unreachable.get(&point).is_none_or(|ids| ids.len() < workers);
+        let batch = match (status, map_data.schedule.tour_order()) {
+            (Some(_), _) => Vec::new(),
+            (None, None) => projected.next_instruction_where(&allowed).into_iter().collect(),
+            (None, Some(_)) => {
+                // a batch does not go past the transfer cap
+                let size = map_data.tracker.criteria().max_transfers
+                    .map_or(map_data.batch_size, |max| map_data.batch_size.min(max.saturating_sub(planned(in_flight))));
+                plan_batch(&projected, size.max(1), &allowed)
+            }
+        };
+        if batch.is_empty() {
+            // with transfers in flight, wait for them before deciding
+            if in_flight == 0 {
+                let status = status.unwrap_or(SolverStatus::Stuck);
+                map_data.status = Some(status);
+                println!("Solver finished after {} transfers: {} (balance {}, {} tiles walked).", map_data.transfers
+                    , status, map_data.balancer.group_balance(), walked);
+            }
+            break
+        }
+        let first = layout.world_position(map_data.grid.point(batch[0].transfer_point));
+        let failed = map_data.unreachable.get(&batch[0].transfer_point);
+        let able: Vec<usize> = (0..idle.len()).filter(|&i| failed.is_none_or(|ids| !ids.contains(&idle[i].id))).collect();
+        let positions: Vec<Vec3> = able.iter().map(|&i| idle[i].position()).collect();
+        let nearest = match nearest(&positions, first) {
+            Some(i) => able[i],
+            // no idle worker can get there, wait for the busy ones
+            None => break
+        };
+        let mut player = idle.swap_remove(nearest);
+        let batch = match map_data.schedule.tour_order() {
+            Some(order) => {
+                let start = layout.grid_position(player.position().truncate())
+                    .map_or_else(|| map_data.balancer.neighborhood().position(batch[0].transfer_point), |(x, y)| (x as f32, y as f32));
+                let tour = order_tour(&projected, start, &batch, order);
+                println!("Batch of {} transfers sent to worker {}, {} tiles to walk.", tour.len(), player.id
+                    , tour_length(&map_data.balancer, start, &tour));
+                tour
+            },
+            None => batch
+        };
+        for i in batch {
+            let grid_pos = map_data.grid.point(i.transfer_point);
+            recolor(&mut tile_query, grid_pos, player.color);
+            let from_group = projected.group(i.transfer_point).expect("only group tiles are transferred");
+            println!("Instruction sent to worker {}. Moving to point {:?}", player.id, grid_pos);
+            player.queue_instruction(Instruction {transfer_point: grid_pos, from_group, to_group: i.to_group, improving: true});
+            projected.reassign_group(i.transfer_point, i.to_group);
+            lock(&mut locked, i.transfer_point);
+            in_flight += 1;
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use bevy::{prelude::*, diagnostic::{Diagnostics, FrameTimeDiagnosticsPlugin}};
+use crate::{map::MapData, player::Player, solver_control::SolverControl};
 
 #[derive(Component)]
 struct FPSText;
@@ -7,6 +8,10 @@ struct FPSText;
 #[derive(Component)]
 struct ColorText;
 
+/// Whether the solver is running, paused or finished, and why.
+#[derive(Component)]
+struct SolverStatusText;
+
 pub struct FPSPlugin;
 
 impl Plugin for FPSPlugin {
@@ -14,7 +19,8 @@ impl Plugin for FPSPlugin {
         app.add_plugin(FrameTimeDiagnosticsPlugin::default())
             .add_startup_system(spawn_ui_text)
             .add_system(text_update_system)
-            .add_system(text_color_system);
+            .add_system(text_color_system)
+            .add_system(solver_status_system);
     }
 }
 
@@ -87,6 +93,29 @@ fn spawn_ui_text(mut commands: Commands, asset_server: Res<AssetServer>) {
         })
         .insert(FPSText);
 
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                align_self: AlignSelf::FlexEnd,
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    top: Val::Px(5.0),
+                    right: Val::Px(15.0),
+                    ..default()
+                },
+                ..default()
+            },
+            text: Text::from_section(
+                "",
+                TextStyle {
+                    font: asset_server.load("fonts/ArchitectsDaughter-Regular.ttf"),
+                    font_size: 30.0,
+                    color: Color::WHITE,
+                },
+            ),
+            ..default()
+        })
+        .insert(SolverStatusText);
 }
 
 
@@ -113,4 +142,21 @@ fn text_color_system(time: Res<Time>, mut query: Query<&mut Text, With<ColorText
             alpha: 1.0
         };
     }
-}
\ No newline at end of file
+}
+fn solver_status_system(map_query: Query<&MapData>, control: Res<SolverControl>, player_query: Query<&Player>
+    , mut query: Query<&mut Text, With<SolverStatusText>>
+){
+    let map_data = match map_query.get_single() {
+        Ok(map_data) => map_data,
+        Err(_) => return
+    };
+    let state = match map_data.status {
+        Some(status) => status.to_string(),
+        None if control.paused => "paused".to_string(),
+        None => "running".to_string()
+    };
+    for mut text in query.iter_mut() {
+        text.sections[0].value = format!("Solver: {}\nTransfers: {}\nBalance: {:.2}\nWalked: {} tiles"
+            , state, map_data.transfers, map_data.group_balance, player_query.iter().map(|p| p.distance).sum::<usize>());
+    }
+}
@@ -0,0 +1,106 @@
+use balancer::{Cell, Grid};
+use rand::{rngs::StdRng, Rng};
+use std::{cmp::Reverse, collections::BinaryHeap, str::FromStr};
+
+/// Kinds of tiles a worker cannot walk over. Group tiles can always be walked over,
+/// they are where the transfers happen.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Terrain {
+    Wall,
+    Empty
+}
+
+impl FromStr for Terrain {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Terrain, String> {
+        match s.trim() {
+            "wall" => Ok(Terrain::Wall),
+            "empty" => Ok(Terrain::Empty),
+            other => Err(format!("unknown terrain '{}', expected wall or empty", other))
+        }
+    }
+}
+
+/// Parses a comma separated list of terrains, like `wall,empty`.
+pub fn parse_terrains(s: &str) -> Result<Vec<Terrain>, String> {
+    s.split(',').filter(|t| !t.trim().is_empty()).map(str::parse).collect()
+}
+
+/// Which tiles of the map a worker can walk over, taken from the map at startup.
+pub struct Walkability {
+    grid: Grid,
+    passable: Vec<bool>
+}
+
+impl Walkability {
+    pub fn new(grid: Grid, tile_groups: &[Cell], impassable: &[Terrain]) -> Walkability {
+        let passable = tile_groups.iter().map(|cell| match cell {
+            Cell::Wall => !impassable.contains(&Terrain::Wall),
+            Cell::Empty => !impassable.contains(&Terrain::Empty),
+            Cell::Group(_) => true
+        }).collect();
+        Walkability {grid, passable}
+    }
+
+    pub fn is_passable(&self, (x, y): (usize, usize)) -> bool {
+        x < self.grid.width && y < self.grid.height && self.passable[self.grid.index((x, y))]
+    }
+
+    /// A shortest 4-connected path from `start` to `goal` over passable tiles, by A* with the
+    /// Manhattan distance as heuristic. The path leaves out `start` and ends with `goal`.
+    /// `start` itself may be impassable, so that a worker can always step off its tile.
+    /// Among equally short paths `rng` picks one, so workers do not all walk the same lane.
+    /// `None` when `goal` cannot be reached.
+    pub fn find_path(&self, start: (usize, usize), goal: (usize, usize), rng: &mut StdRng)
+        -> Option<Vec<(usize, usize)>>
+    {
+        if !self.is_passable(goal) || start.0 >= self.grid.width || start.1 >= self.grid.height {
+            return None
+        }
+        if start == goal {
+            return Some(Vec::new())
+        }
+        let (start, goal) = (self.grid.index(start), self.grid.index(goal));
+        let heuristic = |point: usize| {
+            let ((x, y), (gx, gy)) = (self.grid.point(point), self.grid.point(goal));
+            x.abs_diff(gx) + y.abs_diff(gy)
+        };
+        let mut cost = vec![usize::MAX; self.grid.len()];
+        let mut came_from = vec![usize::MAX; self.grid.len()];
+        // ordered by estimated length, then closeness to the goal, then at random
+        let mut open = BinaryHeap::new();
+        cost[start] = 0;
+        open.push(Reverse((heuristic(start), heuristic(start), rng.gen::<u32>(), start)));
+        while let Some(Reverse((_, _, _, point))) = open.pop() {
+            if point == goal {
+                let mut path = vec![self.grid.point(goal)];
+                let mut current = goal;
+                while came_from[current] != start {
+                    current = came_from[current];
+                    path.push(self.grid.point(current));
+                }
+                path.reverse();
+                return Some(path)
+            }
+            let (x, y) = self.grid.point(point);
+            let steps = [
+                (x.checked_sub(1), Some(y)), (Some(x + 1), Some(y)), (Some(x), y.checked_sub(1)), (Some(x), Some(y + 1))
+            ];
+            for next in steps.into_iter().filter_map(|(x, y)| Some((x?, y?))) {
+                if !self.is_passable(next) {
+                    continue
+                }
+                let next = self.grid.index(next);
+                let next_cost = cost[point] + 1;
+                if next_cost < cost[next] {
+                    cost[next] = next_cost;
+                    came_from[next] = point;
+                    let h = heuristic(next);
+                    open.push(Reverse((next_cost + h, h, rng.gen::<u32>(), next)));
+                }
+            }
+        }
+        None
+    }
+}
@@ -1,7 +1,10 @@
-use bevy::{prelude::*};
-use rand::{Rng, thread_rng, seq::SliceRandom};
+use bevy::{prelude::*, ecs::system::EntityCommands};
+use rand::rngs::StdRng;
 use bevy_inspector_egui::Inspectable;
-use crate::{ascii::{spawn_ascii_sprite, AsciiSheet}, map::{TILE_SIZE, WORLD_X, WORLD_Y, COLORS, MapData, Instruction, Tile}};
+use crate::{ascii::{spawn_ascii_sprite, AsciiSheet}, map::{TILE_SIZE, MapData, MapLayout, MapSeed, Instruction, Tile, Palette, TransferCompleted}};
+use crate::pathfinding::Walkability;
+use crate::settings::parsed_setting;
+use crate::solver_control::SolverControl;
 use bevy_easings::*;
 use std::collections::VecDeque;
 
@@ -27,6 +30,19 @@ impl Movement {
             Movement::Right => Vec2::new(1.0, 0.0)
         }
     }
+
+    /// The move from a tile to a 4-connected neighbor.
+    fn between(from: (usize, usize), to: (usize, usize)) -> Movement {
+        if to.0 < from.0 {
+            Movement::Left
+        } else if to.0 > from.0 {
+            Movement::Right
+        } else if to.1 < from.1 {
+            Movement::Down
+        } else {
+            Movement::Up
+        }
+    }
     // fn random() -> Movement {
     //     let mut rng = rand::thread_rng();
     //     match rng.gen_range(0..4) {
@@ -38,68 +54,114 @@ impl Movement {
     // }
 }
 
+/// A worker walking to the tiles the solver hands it, one instruction at a time.
+/// `--workers N` (or `BALANCE_WORKERS`) spawns N of them.
 #[derive(Component, Inspectable)]
 pub struct Player {
-    speed: f32,
+    #[inspectable(ignore)]
+    pub id: usize,
+    /// Whether the camera follows this worker. With several set, it follows the lowest id.
+    pub follow: bool,
+    #[inspectable(ignore)]
+    pub color: Color,
+    /// Tiles walked so far.
+    pub distance: usize,
+    /// Tiles per second. Set with `--walk-speed` or `BALANCE_WALK_SPEED`.
+    pub speed: f32,
+    /// Where the worker is, or will be once its current step is done.
+    #[inspectable(ignore)]
+    position: Vec3,
     #[inspectable(ignore)]
     pub state: State,
     interval: f32,
     #[inspectable(ignore)]
     move_queue: Vec<Movement>,
     #[inspectable(ignore)]
-    instruction_queue:VecDeque<Instruction>
+    instruction_queue:VecDeque<Instruction>,
+    #[inspectable(ignore)]
+    rng: StdRng
+
+}
+
+/// `MapSeed::rng` stream of the route planning. Worker `i` draws from `ROUTE_STREAM + (i << 32)`,
+/// so a single worker keeps the routes it had before there were several.
+const ROUTE_STREAM: u64 = 1;
+
+/// A color per worker: red for the first one, then hues spread around the color wheel.
+fn worker_color(id: usize) -> Color {
+    Color::hsl((id as f32 * 137.5) % 360.0, 1.0, 0.5)
+}
 
+/// Index of the position in `positions` closest to `target`, by Manhattan distance.
+pub fn nearest(positions: &[Vec3], target: Vec2) -> Option<usize> {
+    positions.iter()
+        .map(|p| (p.x - target.x).abs() + (p.y - target.y).abs())
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(i, _)| i)
 }
 
+/// Walk speed in tiles per second, unless set with `--walk-speed`.
+const WALK_SPEED: f32 = 20.0;
+const MIN_WALK_SPEED: f32 = 0.1;
+
 impl Player {
+    /// Where the worker is, or will be once its current step is done.
+    pub fn position(&self) -> Vec3 {
+        self.position
+    }
+
+    /// Neither walking nor holding an instruction.
+    pub fn is_idle(&self) -> bool {
+        matches!(self.state, State::Idle) && self.instruction_queue.is_empty()
+    }
+
+    /// The instructions not carried out yet, the current one first.
+    pub fn instructions(&self) -> impl Iterator<Item = &Instruction> {
+        self.instruction_queue.iter()
+    }
+
+
     pub fn queue_instruction(&mut self, instruction:Instruction){
         self.instruction_queue.push_back(instruction);
     }
 
-    fn route_planning(current_pos: Vec3, target: Vec3) -> Vec<Movement> {
+    /// Drops the route and every instruction not carried out yet, and returns those instructions.
+    pub fn cancel_instructions(&mut self) -> Vec<Instruction> {
+        self.move_queue.clear();
+        self.interval = 0.0;
+        self.state = State::Idle;
+        self.instruction_queue.drain(..).collect()
+    }
 
-        let diff = (target - current_pos) / TILE_SIZE;
-        let x_diff = diff.x as i32;
-        let y_diff = diff.y as i32;
-        let x_abs = x_diff.abs() as u32;
-        let y_abs = y_diff.abs() as u32;
-        let sum = x_abs + y_abs;
-    
-        let x_move = if x_diff < 0 {
-            Movement::Left
-        } else {
-            Movement::Right
-        };
-    
-        let y_move = if y_diff < 0 {
-            Movement::Down
-        } else {
-            Movement::Up
-        };
-        
-        let mut random_vec:Vec<u32> = (0..sum).collect();
-        random_vec.shuffle(&mut thread_rng());
-        random_vec.iter().map(|x| {
-            if x < &x_abs {
-                x_move
-            } else {
-                y_move
-            }
-        }).collect()
-    
-    } 
+    /// The moves along a shortest path from `current_pos` to `target` around the impassable
+    /// tiles, the last move first. `None` when `target` cannot be reached.
+    fn route_planning(current_pos: Vec3, target: (usize, usize), layout: &MapLayout, walkability: &Walkability
+        , rng: &mut StdRng) -> Option<Vec<Movement>>
+    {
+        let start = layout.grid_position(current_pos.truncate())?;
+        let path = walkability.find_path(start, target, rng)?;
+        let mut moves: Vec<Movement> = std::iter::once(start).chain(path.iter().copied())
+            .zip(path.iter().copied())
+            .map(|(from, to)| Movement::between(from, to))
+            .collect();
+        moves.reverse();
+        Some(moves)
+    }
 
-    fn start_moving(&mut self, player_pos:Vec3){
-        let job = self.instruction_queue.front();
-        match job {
-            Some(j) => {
-                let x:f32 = (j.transfer_point.0 as f32 + WORLD_X[0] as f32) * TILE_SIZE;
-                let y:f32 = (j.transfer_point.1 as f32 + WORLD_Y[0] as f32) * TILE_SIZE;
-                let target:Vec3 = Vec3::new(x, y, player_pos.z);
-                self.move_queue.append(&mut Player::route_planning(player_pos, target));
+    /// Plans the route to the first instruction. False when its tile cannot be reached.
+    fn start_moving(&mut self, player_pos:Vec3, layout: &MapLayout, walkability: &Walkability) -> bool {
+        let target = match self.instruction_queue.front() {
+            Some(j) => j.transfer_point,
+            None => return true
+        };
+        match Player::route_planning(player_pos, target, layout, walkability, &mut self.rng) {
+            Some(mut route) => {
+                self.move_queue.append(&mut route);
                 self.state = State::Moving;
+                true
             },
-            _ => {}
+            None => false
         }
     }
 
@@ -111,33 +173,52 @@ impl Plugin for PlayerPlugin {
     fn build(&self, app:&mut App) {
         app.add_startup_system(spawn_player)
         .add_system(player_control.label("movement"))
+        .add_system(cycle_follow.before("movement"))
         .add_system(camera_follow.after("movement"));
     }
 }
 
-fn spawn_player(mut commands: Commands, ascii: Res<AsciiSheet>){
-    let player = spawn_ascii_sprite(&mut commands, &ascii, 8, Color::rgb(1.,0.,0.), Vec3::new(0.0,0.0,900.0), (0,0));
-    // let menu = spawn_menu(&mut commands, asset_server);
-    // let route = route_planning(Vec3::new(0., 0., 0.), Vec3::new(160., 320., 0.));
-
-    commands.entity(player)
-        .insert(Name::new("Player"))
-        .insert(Player {speed: TILE_SIZE, state: State::Idle, interval: 0.0
-            , move_queue: Vec::new(), instruction_queue: VecDeque::new()});
-
+fn spawn_player(mut commands: Commands, ascii: Res<AsciiSheet>, seed: Res<MapSeed>, layout: Res<MapLayout>){
+    let workers = parsed_setting("workers", 1usize).max(1);
+    let speed = parsed_setting("walk-speed", WALK_SPEED).max(MIN_WALK_SPEED);
+    let (width, height) = (layout.grid.width, layout.grid.height);
+    for id in 0..workers {
+        // side by side from the map center
+        let x = (width / 2 + id).min(width.saturating_sub(2));
+        let position = layout.world_position((x, height / 2));
+        let color = worker_color(id);
+        let position = position.extend(900.0);
+        let player = spawn_ascii_sprite(&mut commands, &ascii, 8, color, position, (0,0));
+        commands.entity(player)
+            .insert(Name::new(format!("Player {}", id)))
+            .insert(Player {id, follow: id == 0, color, distance: 0, speed, position, state: State::Idle, interval: 0.0
+                , move_queue: Vec::new(), instruction_queue: VecDeque::new()
+                , rng: seed.rng(ROUTE_STREAM + ((id as u64) << 32))});
+    }
 }
 
-fn player_control(mut query: Query<(&mut Player, &mut TextureAtlasSprite, &mut Transform), With<Player>>
+#[allow(clippy::too_many_arguments)]
+fn player_control(mut commands: Commands
+    , mut query: Query<(Entity, &mut Player, &mut TextureAtlasSprite, &Transform), With<Player>>
     // , keyboard: Res<Input<KeyCode>>
     , mut map_query: Query<&mut MapData>
-    , mut tile_query: Query<(&Tile, &mut TextureAtlasSprite), (With<Tile>, Without<Player>)>
+    , mut tile_query: TileSprites
+    , palette: Res<Palette>
+    , layout: Res<MapLayout>
+    , walkability: Res<Walkability>
+    , mut transfers: EventWriter<TransferCompleted>
+    , control: Res<SolverControl>
     , t: Res<Time>
 ){
-    let (mut p, mut sprite, mut transform) = query.single_mut();
     let mut map_data = map_query.single_mut();
-    // (std::f32::consts::PI * 0.75 * t.seconds_since_startup() as f32).sin().abs()
-    sprite.color.set_a((std::f32::consts::PI * 0.8 * t.seconds_since_startup() as f32).sin().abs());
-    let current_pos = transform.translation;
+    for (entity, p, mut sprite, transform) in query.iter_mut() {
+        // (std::f32::consts::PI * 0.75 * t.seconds_since_startup() as f32).sin().abs()
+        sprite.color.set_a((std::f32::consts::PI * 0.8 * t.seconds_since_startup() as f32).sin().abs());
+        if control.running() {
+            move_worker(commands.entity(entity), p, transform, &mut map_data, &mut tile_query, &palette, &layout, &walkability, &mut transfers
+                , t.delta_seconds());
+        }
+    }
     
     // if keyboard.just_pressed(KeyCode::C){
     //     let x = ((transform.translation.x / TILE_SIZE) - WORLD_X[0] as f32) as usize;
@@ -148,82 +229,122 @@ fn player_control(mut query: Query<(&mut Player, &mut TextureAtlasSprite, &mut T
     //     println!("{:?}", neighbors);
     // }
 
+    // if keyboard.just_pressed(KeyCode::Up){
+    //     transform.translation += get_move_delta(current_pos, Movement::Up, multiplier);
+    // }
+    // else if keyboard.just_pressed(KeyCode::Down){
+    //     transform.translation += get_move_delta(current_pos, Movement::Down, multiplier);
+    // }
+    // else if keyboard.just_pressed(KeyCode::Left){
+    //     transform.translation += get_move_delta(current_pos, Movement::Left, multiplier);
+    // }
+    // else if keyboard.just_pressed(KeyCode::Right){
+    //     transform.translation += get_move_delta(current_pos, Movement::Right, multiplier);
+    // }
+
+}
+
+/// The tile sprites, for recoloring tiles.
+pub type TileSprites<'w, 's> = Query<'w, 's, (&'static Tile, &'static mut TextureAtlasSprite), (With<Tile>, Without<Player>)>;
+
+/// Moves a worker one step along its route, or carries out its instruction when it has arrived.
+/// Each step is eased from where the sprite is to the next tile, over the time the step takes.
+#[allow(clippy::too_many_arguments)]
+fn move_worker(mut worker: EntityCommands, mut p: Mut<Player>, transform: &Transform
+    , map_data: &mut MapData
+    , tile_query: &mut TileSprites
+    , palette: &Palette
+    , layout: &MapLayout
+    , walkability: &Walkability
+    , transfers: &mut EventWriter<TransferCompleted>
+    , dt: f32
+){
     match p.state {
         State::Moving => {
-            p.interval += t.delta_seconds();
-            if p.interval > 0.05 {
-                p.interval -= 0.05;
+            let step_time = 1.0 / p.speed.max(MIN_WALK_SPEED);
+            p.interval += dt;
+            if p.interval > step_time {
+                p.interval -= step_time;
                 if p.move_queue.is_empty(){
                     if let Some(instr) = p.instruction_queue.pop_front(){
                         // empty out instruction when it's done.
-                        map_data.reassign_group(instr.transfer_point, instr.to_group, 2);
-                        for (tile, mut tile_sprite) in tile_query.iter_mut() {
-                            if tile.loc == instr.transfer_point {
-                                tile_sprite.color = COLORS[instr.to_group as usize];
-                                break;
-                            }
+                        // other workers may have changed the map on the way
+                        let group = if map_data.can_carry_out(&instr) {
+                            transfers.send(map_data.reassign_group(instr.transfer_point, instr.to_group));
+                            map_data.transfers += 1;
+                            Some(instr.to_group)
+                        } else {
+                            println!("Worker {} dropped the transfer of {:?}: it no longer lowers the balance."
+                                , p.id, instr.transfer_point);
+                            map_data.balancer.group(map_data.grid.index(instr.transfer_point))
+                        };
+                        if let Some(group) = group {
+                            recolor(tile_query, instr.transfer_point, palette.color(group));
                         }
                         p.interval = 0.0;
                         p.state = State::Idle;
                     }
                 } else {
                     let step = p.move_queue.pop().unwrap();
-                    transform.translation += get_move_delta(current_pos, step, 1.0);
+                    p.position += (step.v() * TILE_SIZE).extend(0.0);
+                    p.distance += 1;
+                    worker.insert(transform.ease_to(
+                        Transform {translation: p.position, ..*transform}
+                        , EaseMethod::Linear
+                        , EasingType::Once {duration: std::time::Duration::from_secs_f32(step_time)}
+                    ));
                 }
             }
         },
         State::Idle => {
-            if !p.instruction_queue.is_empty(){
-                p.start_moving(transform.translation);
+            let position = p.position;
+            if !p.instruction_queue.is_empty() && !p.start_moving(position, layout, walkability) {
+                let instr = p.instruction_queue.pop_front().expect("an instruction to start");
+                println!("Worker {} cannot reach {:?}: no path around the impassable tiles. The transfer is dropped."
+                    , p.id, instr.transfer_point);
+                let index = map_data.grid.index(instr.transfer_point);
+                map_data.unreachable.entry(index).or_default().insert(p.id);
+                if let Some(group) = map_data.balancer.group(index) {
+                    recolor(tile_query, instr.transfer_point, palette.color(group));
+                }
             }
         }
     }
-
-    // if keyboard.just_pressed(KeyCode::Up){
-    //     transform.translation += get_move_delta(current_pos, Movement::Up, multiplier);
-    // }
-    // else if keyboard.just_pressed(KeyCode::Down){
-    //     transform.translation += get_move_delta(current_pos, Movement::Down, multiplier);
-    // }
-    // else if keyboard.just_pressed(KeyCode::Left){
-    //     transform.translation += get_move_delta(current_pos, Movement::Left, multiplier);
-    // }
-    // else if keyboard.just_pressed(KeyCode::Right){
-    //     transform.translation += get_move_delta(current_pos, Movement::Right, multiplier);
-    // }
-
 }
 
-fn get_move_delta(current_pos: Vec3, dir: Movement, length: f32) -> Vec3{
-    let temp = dir.v();
-    let pos = Vec3::new(temp.x, temp.y, 0.0);
-    let target = current_pos + length * pos * TILE_SIZE;
-    overshoot_check(target, current_pos)
+/// Paints the tile at `point` in `color`.
+pub fn recolor(tile_query: &mut TileSprites, point: (usize, usize), color: Color) {
+    for (tile, mut tile_sprite) in tile_query.iter_mut() {
+        if tile.loc == point {
+            tile_sprite.color = color;
+            break;
+        }
+    }
 }
 
-fn overshoot_check(target: Vec3, player: Vec3) -> Vec3 {
-
-    if target.x <= WORLD_X[0] as f32 * TILE_SIZE {
-        return Vec3::new((WORLD_X[0]+1) as f32 * TILE_SIZE - player.x, 0., 0.)
-    }
-    if WORLD_X[1] as f32 * TILE_SIZE <= target.x {
-        return Vec3::new((WORLD_X[1]-1) as f32 * TILE_SIZE - player.x , 0., 0.)
-    }
-    if target.y <= WORLD_Y[0] as f32 * TILE_SIZE {
-        return Vec3::new(0., (WORLD_Y[0]+1) as f32 * TILE_SIZE - player.y, 0.)
+/// F hands the camera to the next worker.
+fn cycle_follow(keyboard: Res<Input<KeyCode>>, mut query: Query<&mut Player>) {
+    if !keyboard.just_pressed(KeyCode::F) {
+        return
     }
-    if WORLD_Y[1] as f32 * TILE_SIZE <= target.y {
-        return Vec3::new(0., (WORLD_Y[1]-1) as f32 * TILE_SIZE - player.y, 0.)
+    let workers = query.iter().count();
+    let followed = query.iter().filter(|p| p.follow).map(|p| p.id).min();
+    let next = followed.map_or(0, |id| (id + 1) % workers);
+    for mut p in query.iter_mut() {
+        p.follow = p.id == next;
     }
-    target - player
+    println!("Camera follows worker {}.", next);
 }
 
 fn camera_follow(
     mut commands: Commands,
-    player_query: Query<&Transform, With<Player>>,
+    player_query: Query<(&Transform, &Player)>,
     mut camera_query: Query<(Entity, &Transform), (With<Camera2d>, Without<Player>)>
 ){
-    let transform = player_query.single();
+    let transform = match player_query.iter().filter(|(_, p)| p.follow).min_by_key(|(_, p)| p.id) {
+        Some((transform, _)) => transform,
+        None => return
+    };
     let (camera, camera_transform) = camera_query.single_mut();
     if (camera_transform.translation.x - transform.translation.x).abs() > 760.0 {
         let target = Vec3::new(transform.translation.x, camera_transform.translation.y, camera_transform.translation.z);
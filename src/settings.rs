@@ -0,0 +1,23 @@
+/// Reads a demo setting, from `--<name> <value>` on the command line or else from
+/// the `BALANCE_<NAME>` environment variable.
+pub fn setting(name: &str) -> Option<String> {
+    let flag = format!("--{}", name);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next()
+        }
+    }
+    std::env::var(format!("BALANCE_{}", name.to_uppercase().replace('-', "_"))).ok()
+}
+
+/// Like `setting`, parsed. Falls back to `default` (with a warning) when the value does not parse.
+pub fn parsed_setting<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match setting(name) {
+        Some(v) => v.parse().unwrap_or_else(|_| {
+            println!("Ignoring setting {}: cannot parse '{}'.", name, v);
+            default
+        }),
+        None => default
+    }
+}
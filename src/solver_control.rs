@@ -0,0 +1,103 @@
+use bevy::{prelude::*, ecs::event::{Events, ManualEventReader}};
+use crate::map::{MapData, Palette, TransferCompleted};
+use crate::player::{recolor, Player, TileSprites};
+
+/// Pausing, stepping and undoing the solver from the keyboard:
+/// Space pauses and resumes, N carries out one more transfer while paused,
+/// Z undoes the last transfer and Y redoes it. Undo and redo pause the solver.
+#[derive(Default)]
+pub struct SolverControl {
+    pub paused: bool,
+    /// Paused, but let the next transfer through.
+    step: bool,
+    /// Transfers carried out so far, the last one at the end.
+    history: Vec<TransferCompleted>,
+    /// Transfers undone, the last one undone at the end.
+    undone: Vec<TransferCompleted>
+}
+
+impl SolverControl {
+    /// Whether the solver and the player may go on.
+    pub fn running(&self) -> bool {
+        !self.paused || self.step
+    }
+}
+
+pub struct SolverControlPlugin;
+
+impl Plugin for SolverControlPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SolverControl>()
+            .add_system(solver_control.after("movement"));
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn solver_control(keyboard: Res<Input<KeyCode>>
+    , mut control: ResMut<SolverControl>
+    , mut events: ResMut<Events<TransferCompleted>>
+    , mut reader: Local<ManualEventReader<TransferCompleted>>
+    , mut map_query: Query<&mut MapData>
+    , mut player_query: Query<&mut Player>
+    , mut tile_query: TileSprites
+    , palette: Res<Palette>
+){
+    // transfers carried out by the player since the last frame
+    for transfer in reader.iter(&events) {
+        control.history.push(transfer.clone());
+        control.undone.clear();
+        control.step = false;
+    }
+
+    if keyboard.just_pressed(KeyCode::Space) {
+        control.paused = !control.paused;
+        control.step = false;
+        println!("Solver {}.", if control.paused { "paused" } else { "resumed" });
+    }
+    if keyboard.just_pressed(KeyCode::N) && control.paused {
+        control.step = true;
+    }
+    let undo = keyboard.just_pressed(KeyCode::Z);
+    let redo = keyboard.just_pressed(KeyCode::Y);
+    if !undo && !redo {
+        return
+    }
+    control.paused = true;
+    control.step = false;
+
+    let mut map_data = map_query.single_mut();
+    // the instructions on their way were planned for the map as it is now
+    let cancelled: Vec<_> = player_query.iter_mut().flat_map(|mut p| p.cancel_instructions()).collect();
+    for instr in cancelled {
+        if let Some(group) = map_data.balancer.group(map_data.grid.index(instr.transfer_point)) {
+            recolor(&mut tile_query, instr.transfer_point, palette.color(group));
+        }
+    }
+
+    if undo {
+        match control.history.pop() {
+            Some(transfer) => {
+                events.send(map_data.reassign_group(transfer.point, transfer.from));
+                map_data.transfers = map_data.transfers.saturating_sub(1);
+                recolor(&mut tile_query, transfer.point, palette.color(transfer.from));
+                println!("Undid transfer of {:?} to group {}.", transfer.point, transfer.to);
+                control.undone.push(transfer);
+            },
+            None => println!("Nothing to undo.")
+        }
+    } else {
+        match control.undone.pop() {
+            Some(transfer) => {
+                events.send(map_data.reassign_group(transfer.point, transfer.to));
+                map_data.transfers += 1;
+                recolor(&mut tile_query, transfer.point, palette.color(transfer.to));
+                println!("Redid transfer of {:?} to group {}.", transfer.point, transfer.to);
+                control.history.push(transfer);
+            },
+            None => println!("Nothing to redo.")
+        }
+    }
+    map_data.restart();
+    // the undo or redo itself is not a new transfer
+    reader.iter(&events).for_each(drop);
+}
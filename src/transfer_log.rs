@@ -0,0 +1,156 @@
+use bevy::{prelude::*};
+use std::{collections::VecDeque, fs::File, io::{BufRead, BufReader, BufWriter, Write}};
+use crate::map::{Instruction, MapData, MapLayout, MapSeed, TransferCompleted};
+use crate::player::{nearest, recolor, Player, TileSprites};
+use crate::settings::setting;
+use crate::solver_control::SolverControl;
+
+/// Column header of a transfer log. Lines starting with `//` are comments.
+const HEADER: &str = "x,y,from,to,value,balance_before,balance_after";
+
+/// Writes every `TransferCompleted` to the file given with `--record FILE` (or `BALANCE_RECORD`).
+struct Recorder {
+    writer: BufWriter<File>
+}
+
+/// Transfers still to re-apply from the log given with `--replay FILE` (or `BALANCE_REPLAY`).
+/// While this resource exists the solver does not run.
+pub struct Replay {
+    transfers: VecDeque<TransferCompleted>,
+    interval: f32
+}
+
+pub struct TransferLogPlugin;
+
+impl Plugin for TransferLogPlugin {
+    fn build(&self, app: &mut App) {
+        let seed = app.world.resource::<MapSeed>().0;
+        if let Some(path) = setting("record") {
+            match create_log(&path, seed) {
+                Ok(writer) => {
+                    app.insert_resource(Recorder {writer})
+                        .add_system(record_transfers.after("movement"));
+                },
+                Err(e) => println!("Cannot record to {}: {}", path, e)
+            }
+        }
+        if let Some(path) = setting("replay") {
+            match read_log(&path) {
+                Ok((log_seed, transfers)) => {
+                    if let Some(log_seed) = log_seed.filter(|&s| s != seed) {
+                        println!("{} was recorded with seed {}, run with --seed {} to replay it on the same map."
+                            , path, log_seed, log_seed);
+                    }
+                    println!("Replaying {} transfers from {}.", transfers.len(), path);
+                    app.insert_resource(Replay {transfers, interval: 0.0})
+                        .add_system(replay_transfers.label("algorithm"));
+                },
+                Err(e) => {
+                    eprintln!("Cannot replay {}: {}", path, e);
+                    std::process::exit(1)
+                }
+            }
+        }
+    }
+}
+
+fn create_log(path: &str, seed: u64) -> std::io::Result<BufWriter<File>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "// seed: {}", seed)?;
+    if let Some(map) = setting("map") {
+        writeln!(writer, "// map: {}", map)?;
+    }
+    writeln!(writer, "{}", HEADER)?;
+    writer.flush()?;
+    Ok(writer)
+}
+
+/// Reads a log written by the recorder: the seed from its `// seed:` line, and the transfers.
+fn read_log(path: &str) -> Result<(Option<u64>, VecDeque<TransferCompleted>), Box<dyn std::error::Error>> {
+    let mut seed = None;
+    let mut transfers = VecDeque::new();
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if let Some(comment) = line.strip_prefix("//") {
+            if let Some(s) = comment.trim().strip_prefix("seed:") {
+                seed = s.trim().parse().ok();
+            }
+            continue
+        }
+        if line.is_empty() || line == HEADER {
+            continue
+        }
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+        let bad = || format!("line {}: expected {}", i + 1, HEADER);
+        if fields.len() != 7 {
+            return Err(bad().into())
+        }
+        transfers.push_back(TransferCompleted {
+            point: (fields[0].parse().map_err(|_| bad())?, fields[1].parse().map_err(|_| bad())?),
+            from: fields[2].parse().map_err(|_| bad())?,
+            to: fields[3].parse().map_err(|_| bad())?,
+            value: fields[4].parse().map_err(|_| bad())?,
+            balance_before: fields[5].parse().map_err(|_| bad())?,
+            balance_after: fields[6].parse().map_err(|_| bad())?
+        });
+    }
+    Ok((seed, transfers))
+}
+
+fn record_transfers(mut events: EventReader<TransferCompleted>, mut recorder: ResMut<Recorder>) {
+    for e in events.iter() {
+        let written = writeln!(recorder.writer, "{},{},{},{},{},{},{}", e.point.0, e.point.1, e.from, e.to
+            , e.value, e.balance_before, e.balance_after)
+            .and_then(|_| recorder.writer.flush());
+        if let Err(e) = written {
+            println!("Cannot record transfer: {}", e);
+        }
+    }
+}
+
+/// Hands the logged transfers to the workers one at a time, in place of `start_algorithm`.
+/// A transfer waits until the one before it is carried out, so the order is the recorded one.
+fn replay_transfers(mut player_query: Query<&mut Player>
+    , map_query: Query<&MapData>
+    , mut tile_query: TileSprites
+    , layout: Res<MapLayout>
+    , mut replay: ResMut<Replay>
+    , control: Res<SolverControl>
+    , t: Res<Time>
+){
+    if !control.running() {
+        return
+    }
+    let map_data = map_query.single();
+    replay.interval += t.delta_seconds();
+    if replay.interval <= 0.05 || !player_query.iter().all(|p| p.is_idle()) {
+        return
+    }
+    replay.interval = 0.0;
+    let transfer = match replay.transfers.pop_front() {
+        Some(transfer) => transfer,
+        None => return
+    };
+    let (width, height) = (map_data.grid.width, map_data.grid.height);
+    let current = if transfer.point.0 < width && transfer.point.1 < height {
+        map_data.balancer.group(map_data.grid.index(transfer.point))
+    } else {
+        None
+    };
+    if current != Some(transfer.from) || transfer.to >= map_data.balancer.group_count() {
+        println!("Replay stopped: {:?} is not a transfer on this map (tile group {:?}).", transfer, current);
+        replay.transfers.clear();
+        return
+    }
+    let mut workers: Vec<_> = player_query.iter_mut().collect();
+    let positions: Vec<Vec3> = workers.iter().map(|p| p.position()).collect();
+    let player = &mut workers[nearest(&positions, layout.world_position(transfer.point)).expect("a worker")];
+    recolor(&mut tile_query, transfer.point, player.color);
+    println!("Replaying transfer of {:?} from group {} to group {}.", transfer.point, transfer.from, transfer.to);
+    player.queue_instruction(Instruction {transfer_point: transfer.point, from_group: transfer.from, to_group: transfer.to
+        , improving: false});
+    if replay.transfers.is_empty() {
+        println!("Replay finished.");
+    }
+}